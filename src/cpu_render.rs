@@ -0,0 +1,229 @@
+use super::frame::{FrameQuad, GlyphContext, GlyphQuad};
+use super::glyph_cache::{Antialias, CpuAtlasStorage, CpuTexture, ATLAS_SIZE};
+use super::glyph_rasterizer::{Format, SoftwareGlyphRasterizer};
+use super::text::Text;
+
+/// Renderer that composites text into an RGBA image in system memory. This
+/// mirrors the Metal renderer, including glyph caching and blending, without
+/// requiring a window or GPU.
+pub struct Renderer {
+    width: u32,
+    height: u32,
+    glyphs: GlyphContext<CpuAtlasStorage>,
+    glyph_rasterizer: SoftwareGlyphRasterizer,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            glyphs: GlyphContext::new(CpuAtlasStorage),
+            glyph_rasterizer: SoftwareGlyphRasterizer::new(),
+        }
+    }
+
+    pub fn set_target_size(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Sets the glyph cache budget in bytes of atlas space.
+    pub fn set_glyph_cache_budget(&mut self, budget: usize) {
+        self.glyphs.set_budget(budget);
+    }
+
+    /// Enables hinting of glyph outlines.
+    pub fn set_hinting(&mut self, hint: bool) {
+        self.glyphs.set_hinting(hint);
+    }
+
+    /// Sets the antialiasing of glyphs rendered from monochrome outlines.
+    pub fn set_antialias(&mut self, antialias: Antialias) {
+        self.glyphs.set_antialias(antialias);
+    }
+
+    /// Returns the texture of a page in the color or alpha atlas.
+    pub fn atlas_page(&self, is_color: bool, page: usize) -> Option<&CpuTexture> {
+        let pages = if is_color {
+            &self.glyphs.cache.color
+        } else {
+            &self.glyphs.cache.alpha
+        };
+        pages.get(page).map(|atlas| &atlas.texture)
+    }
+
+    /// Returns the number of pages in the color or alpha atlas.
+    pub fn atlas_page_count(&self, is_color: bool) -> usize {
+        if is_color {
+            self.glyphs.cache.color.len()
+        } else {
+            self.glyphs.cache.alpha.len()
+        }
+    }
+
+    pub fn new_frame(&mut self, bg_color: [f32; 4]) -> FrameRenderer<'_> {
+        self.glyphs.begin_frame();
+        FrameRenderer { r: self, bg_color }
+    }
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct FrameRenderer<'a> {
+    r: &'a mut Renderer,
+    bg_color: [f32; 4],
+}
+
+impl<'a> FrameRenderer<'a> {
+    pub fn draw_text(&mut self, x: f32, y: f32, text: &Text) {
+        self.r.glyphs.add_text(x, y, text);
+    }
+
+    /// Renders the frame and returns the resulting RGBA image.
+    pub fn render(self) -> CpuTexture {
+        let rasterizer = &mut self.r.glyph_rasterizer;
+        self.r.glyphs.update_cache(|format, texture, glyphs| {
+            rasterizer.begin(format, ATLAS_SIZE, ATLAS_SIZE);
+            for glyph in glyphs {
+                // The font data is owned by the runs for this frame.
+                unsafe {
+                    rasterizer.add_glyph(glyph);
+                }
+            }
+            rasterizer.write_to(texture);
+        });
+        let mut target = CpuTexture::new(Format::Rgba8, self.r.width, self.r.height);
+        let bg_color = self.bg_color.map(|c| (c.clamp(0., 1.) * 255. + 0.5) as u8);
+        for pixel in target.data.chunks_mut(4) {
+            pixel.copy_from_slice(&bg_color);
+        }
+        for quad in self.r.glyphs.quads() {
+            let quad = match quad {
+                FrameQuad::Glyph(quad) => quad,
+                FrameQuad::Solid { rect, color } => {
+                    fill_rect(&mut target, rect, color);
+                    continue;
+                }
+            };
+            if let Some(atlas) = self.r.glyphs.atlas(quad.format, quad.page) {
+                composite(&mut target, &quad, &atlas.texture);
+            }
+        }
+        target
+    }
+}
+
+/// Blends a glyph quad into the target, sampling the atlas with the nearest
+/// texel at each pixel center. This matches `alpha_frag` and `color_frag`
+/// followed by source over blending, and `subpixel_frag` followed by
+/// blending each color channel with its own coverage.
+fn composite(target: &mut CpuTexture, quad: &GlyphQuad, atlas: &CpuTexture) {
+    let [x0, y0, x1, y1] = quad.rect;
+    let u0 = (quad.uv[0] * atlas.width as f32).round() as i32;
+    let v0 = (quad.uv[1] * atlas.height as f32).round() as i32;
+    let width = (x1 - x0).round() as i32;
+    let height = (y1 - y0).round() as i32;
+    let color = quad.color.map(|c| c as f32 / 255.);
+    let atlas_pixel_size = atlas.format.pixel_size();
+    let atlas_stride = atlas.stride();
+    let target_stride = target.stride();
+    // Pixels whose centers fall inside the quad.
+    let px0 = (x0 - 0.5).ceil().max(0.) as i32;
+    let py0 = (y0 - 0.5).ceil().max(0.) as i32;
+    let px1 = ((x1 - 0.5).ceil() as i32).min(target.width as i32);
+    let py1 = ((y1 - 0.5).ceil() as i32).min(target.height as i32);
+    for py in py0..py1 {
+        let ty = ((py as f32 + 0.5 - y0).floor() as i32)
+            .max(0)
+            .min(height - 1);
+        for px in px0..px1 {
+            let tx = ((px as f32 + 0.5 - x0).floor() as i32)
+                .max(0)
+                .min(width - 1);
+            let texel_offset =
+                (v0 + ty) as usize * atlas_stride + (u0 + tx) as usize * atlas_pixel_size;
+            let offset = py as usize * target_stride + px as usize * 4;
+            let dst = &mut target.data[offset..offset + 4];
+            let src = match quad.format {
+                Format::A8 => {
+                    let coverage = atlas.data[texel_offset] as f32 / 255.;
+                    color.map(|c| c * coverage)
+                }
+                Format::Rgba8 => {
+                    let texel = &atlas.data[texel_offset..texel_offset + 4];
+                    [
+                        texel[0] as f32 / 255. * color[0],
+                        texel[1] as f32 / 255. * color[1],
+                        texel[2] as f32 / 255. * color[2],
+                        texel[3] as f32 / 255. * color[3],
+                    ]
+                }
+                Format::SubpixelRgb | Format::SubpixelBgr => {
+                    let texel = &atlas.data[texel_offset..texel_offset + 4];
+                    let mask = [
+                        texel[0] as f32 / 255. * color[3],
+                        texel[1] as f32 / 255. * color[3],
+                        texel[2] as f32 / 255. * color[3],
+                    ];
+                    blend_subpixel(dst, color, mask);
+                    continue;
+                }
+            };
+            if src[3] == 0. {
+                continue;
+            }
+            let inv_alpha = 1. - src[3];
+            for i in 0..3 {
+                let value = src[i] * src[3] + dst[i] as f32 / 255. * inv_alpha;
+                dst[i] = (value * 255. + 0.5) as u8;
+            }
+            let alpha = src[3] + dst[3] as f32 / 255. * inv_alpha;
+            dst[3] = (alpha * 255. + 0.5) as u8;
+        }
+    }
+}
+
+/// Blends a solid color into the pixels whose centers fall inside the
+/// rectangle. This matches `solid_frag` followed by source over blending.
+fn fill_rect(target: &mut CpuTexture, rect: [f32; 4], color: [u8; 4]) {
+    let [x0, y0, x1, y1] = rect;
+    let color = color.map(|c| c as f32 / 255.);
+    if color[3] == 0. {
+        return;
+    }
+    let inv_alpha = 1. - color[3];
+    let target_stride = target.stride();
+    let px0 = (x0 - 0.5).ceil().max(0.) as i32;
+    let py0 = (y0 - 0.5).ceil().max(0.) as i32;
+    let px1 = ((x1 - 0.5).ceil() as i32).min(target.width as i32);
+    let py1 = ((y1 - 0.5).ceil() as i32).min(target.height as i32);
+    for py in py0..py1 {
+        for px in px0..px1 {
+            let offset = py as usize * target_stride + px as usize * 4;
+            let dst = &mut target.data[offset..offset + 4];
+            for i in 0..3 {
+                let value = color[i] * color[3] + dst[i] as f32 / 255. * inv_alpha;
+                dst[i] = (value * 255. + 0.5) as u8;
+            }
+            let alpha = color[3] + dst[3] as f32 / 255. * inv_alpha;
+            dst[3] = (alpha * 255. + 0.5) as u8;
+        }
+    }
+}
+
+/// Blends a color into a pixel with separate coverage for each color
+/// channel.
+fn blend_subpixel(dst: &mut [u8], color: [f32; 4], mask: [f32; 3]) {
+    for i in 0..3 {
+        let value = color[i] * mask[i] + dst[i] as f32 / 255. * (1. - mask[i]);
+        dst[i] = (value * 255. + 0.5) as u8;
+    }
+    let coverage = mask[0].max(mask[1]).max(mask[2]);
+    let alpha = coverage + dst[3] as f32 / 255. * (1. - coverage);
+    dst[3] = (alpha * 255. + 0.5) as u8;
+}
@@ -1,28 +1,111 @@
+use super::glyph::FontVariation;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
-use swash::{CacheKey, FontDataRef, FontRef};
+use swash::text::cluster::{CharCluster, Status};
+use swash::{CacheKey, FontDataRef, FontRef, NormalizedCoord, Setting};
 
 #[derive(Clone)]
 pub struct Font {
     pub data: Arc<Vec<u8>>,
     pub offset: u32,
     pub key: CacheKey,
+    /// Variation axis settings for this instance of the font.
+    pub variations: Arc<[FontVariation]>,
+    /// Hash of the variation settings. Zero for the default instance.
+    pub variations_key: u64,
 }
 
 impl Font {
     pub fn new(data: Vec<u8>) -> Option<Self> {
-        let font_data = FontDataRef::new(&data)?;
-        let font_ref = font_data.get(0)?;
+        Self::new_with_index(data, 0)
+    }
+
+    /// Creates a font from the face at the specified index in a font
+    /// collection (.ttc/.otc). Index 0 selects the font in a single font
+    /// file.
+    pub fn new_with_index(data: Vec<u8>, index: usize) -> Option<Self> {
+        Self::from_shared(Arc::new(data), index)
+    }
+
+    /// Creates a font for every face in the font data, sharing a single
+    /// copy of the data.
+    pub fn collection(data: Vec<u8>) -> Vec<Self> {
+        let data = Arc::new(data);
+        let count = Self::face_count(&data);
+        (0..count)
+            .filter_map(|index| Self::from_shared(data.clone(), index))
+            .collect()
+    }
+
+    /// Returns the number of faces in the font data.
+    pub fn face_count(data: &[u8]) -> usize {
+        FontDataRef::new(data)
+            .map(|font_data| font_data.len())
+            .unwrap_or(0)
+    }
+
+    pub fn from_file(path: &str) -> Option<Self> {
+        Self::new(std::fs::read(path).ok()?)
+    }
+
+    pub fn from_file_index(path: &str, index: usize) -> Option<Self> {
+        Self::new_with_index(std::fs::read(path).ok()?, index)
+    }
+
+    fn from_shared(data: Arc<Vec<u8>>, index: usize) -> Option<Self> {
+        let font_ref = FontDataRef::new(&data)?.get(index)?;
         let offset = font_ref.offset;
         let key = font_ref.key;
         Some(Self {
-            data: Arc::new(data),
+            data,
             offset,
             key,
+            variations: Arc::from(vec![]),
+            variations_key: 0,
         })
     }
 
-    pub fn from_file(path: &str) -> Option<Self> {
-        Self::new(std::fs::read(path).ok()?)
+    /// Returns a new instance of the font with the specified variation
+    /// axis settings, such as `("wght", 700.0)`.
+    pub fn with_variations<I>(&self, settings: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Setting<f32>>,
+    {
+        let variations: Vec<FontVariation> = settings
+            .into_iter()
+            .map(|setting| {
+                let setting = setting.into();
+                FontVariation {
+                    tag: setting.tag,
+                    value: setting.value,
+                }
+            })
+            .collect();
+        let variations_key = if variations.is_empty() {
+            0
+        } else {
+            let mut hasher = DefaultHasher::new();
+            for var in &variations {
+                var.tag.hash(&mut hasher);
+                var.value.to_bits().hash(&mut hasher);
+            }
+            hasher.finish()
+        };
+        Self {
+            variations: variations.into(),
+            variations_key,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the normalized variation coordinates for the instance.
+    pub fn normalized_coords(&self) -> Vec<NormalizedCoord> {
+        self.as_ref()
+            .variations()
+            .normalized_coords(self.variations.iter().map(|var| (var.tag, var.value)))
+            .collect()
     }
 
     pub fn as_ref(&self) -> FontRef {
@@ -33,3 +116,99 @@ impl Font {
         }
     }
 }
+
+/// Copies the face with the table directory at `offset` out of a font
+/// collection into a standalone font file. Useful for consumers that can
+/// only load the first face of some font data.
+pub fn extract_face(data: &[u8], offset: u32) -> Option<Vec<u8>> {
+    let read_u16 = |at: usize| {
+        let bytes = data.get(at..at + 2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let read_u32 = |at: usize| {
+        let bytes = data.get(at..at + 4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    let base = offset as usize;
+    let num_tables = read_u16(base + 4)? as usize;
+    let header_len = 12 + num_tables * 16;
+    let mut face = Vec::with_capacity(header_len);
+    face.extend_from_slice(data.get(base..base + header_len)?);
+    for i in 0..num_tables {
+        let record = 12 + i * 16;
+        let table_offset = read_u32(base + record + 8)? as usize;
+        let table_len = read_u32(base + record + 12)? as usize;
+        let table = data.get(table_offset..table_offset + table_len)?;
+        let new_offset = face.len() as u32;
+        face[record + 8..record + 12].copy_from_slice(&new_offset.to_be_bytes());
+        face.extend_from_slice(table);
+        // Tables are 4 byte aligned.
+        face.resize((face.len() + 3) & !3, 0);
+    }
+    Some(face)
+}
+
+/// Ordered list of fonts used for layout. Each cluster is rendered with
+/// the first font that supports all of its characters.
+#[derive(Clone)]
+pub struct FontFallback {
+    fonts: Vec<Font>,
+}
+
+impl FontFallback {
+    /// Creates a fallback list from fonts in order of preference.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fonts` is empty.
+    pub fn new(fonts: Vec<Font>) -> Self {
+        assert!(!fonts.is_empty(), "font fallback list must not be empty");
+        Self { fonts }
+    }
+
+    pub fn push(&mut self, font: Font) {
+        self.fonts.push(font);
+    }
+
+    pub fn fonts(&self) -> &[Font] {
+        &self.fonts
+    }
+
+    /// Returns the primary font.
+    pub fn primary(&self) -> &Font {
+        &self.fonts[0]
+    }
+
+    /// Returns the index of the first font that maps every character in the
+    /// cluster or, failing that, the font with the most complete mapping.
+    pub fn select(&self, cluster: &mut CharCluster) -> usize {
+        let mut best = 0;
+        for (i, font) in self.fonts.iter().enumerate() {
+            let charmap = font.as_ref().charmap();
+            match cluster.map(|ch| charmap.map(ch)) {
+                Status::Complete => return i,
+                Status::Keep => best = i,
+                Status::Discard => {}
+            }
+        }
+        best
+    }
+}
+
+impl From<Font> for FontFallback {
+    fn from(font: Font) -> Self {
+        Self::new(vec![font])
+    }
+}
+
+impl From<&Font> for FontFallback {
+    fn from(font: &Font) -> Self {
+        Self::new(vec![font.clone()])
+    }
+}
+
+impl From<&FontFallback> for FontFallback {
+    fn from(fonts: &FontFallback) -> Self {
+        fonts.clone()
+    }
+}
@@ -0,0 +1,623 @@
+use super::font::Font;
+use super::glyph::{Glyph, Synthesis};
+use super::glyph_cache::{
+    Antialias, Atlas, AtlasStorage, GlyphCache, GlyphKey, SubpixelOffset, ATLAS_SIZE,
+};
+use super::glyph_rasterizer::Format;
+use super::text::{DecorationKind, DecorationSegment, DecorationStyle, Text};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use swash::scale::{image::Image, outline::Outline, ScaleContext, Scaler, StrikeWith};
+use swash::zeno::{Origin, Placement, Transform};
+
+/// Glyph cache, scale context and glyphs of the current frame. This holds
+/// the glyph handling shared by the renderers, which only differ in how
+/// glyphs are rasterized into atlas pages and how quads are drawn.
+pub(crate) struct GlyphContext<S: AtlasStorage> {
+    pub cache: GlyphCache<S>,
+    scale_ctx: ScaleContext,
+    pub frame: FrameGlyphs,
+    /// Whether any glyph added to the frame is missing from the cache.
+    cache_miss: bool,
+}
+
+impl<S: AtlasStorage> GlyphContext<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            cache: GlyphCache::new(storage),
+            scale_ctx: ScaleContext::new(),
+            frame: FrameGlyphs::default(),
+            cache_miss: false,
+        }
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.cache.set_budget(budget);
+    }
+
+    pub fn set_hinting(&mut self, hint: bool) {
+        self.frame.hint = hint;
+    }
+
+    pub fn set_antialias(&mut self, antialias: Antialias) {
+        self.frame.antialias = antialias;
+    }
+
+    /// Starts a new frame without any glyphs.
+    pub fn begin_frame(&mut self) {
+        self.cache.begin_frame();
+        self.frame.clear();
+        self.cache_miss = false;
+    }
+
+    /// Positions the glyphs of the text in the frame.
+    pub fn add_text(&mut self, x: f32, y: f32, text: &Text) {
+        if self
+            .frame
+            .add_text(x, y, text, &mut self.scale_ctx, &mut self.cache)
+        {
+            self.cache_miss = true;
+        }
+    }
+
+    /// Returns the atlas page that holds glyphs of the format. Alpha masks
+    /// are stored in the alpha pages and all other formats in the color
+    /// pages.
+    pub fn atlas(&self, format: Format, page: u16) -> Option<&Atlas<S::Texture>> {
+        let pages = if format == Format::A8 {
+            &self.cache.alpha
+        } else {
+            &self.cache.color
+        };
+        pages.get(page as usize)
+    }
+
+    /// Inserts the glyphs of the frame that are missing from the cache and
+    /// calls `rasterize` with the format, texture and new glyphs of each
+    /// atlas page that received any. Existing entries and their texels are
+    /// left untouched.
+    pub fn update_cache(&mut self, mut rasterize: impl FnMut(Format, &mut S::Texture, &[Glyph])) {
+        if !self.cache_miss {
+            return;
+        }
+        self.cache_miss = false;
+        let batches = self
+            .frame
+            .update_cache(&mut self.scale_ctx, &mut self.cache);
+        for ((format, page), glyphs) in &batches {
+            let pages = if *format == Format::A8 {
+                &mut self.cache.alpha
+            } else {
+                &mut self.cache.color
+            };
+            if let Some(atlas) = pages.get_mut(*page as usize) {
+                rasterize(*format, &mut atlas.texture, glyphs);
+            }
+        }
+    }
+
+    /// Returns the quads of the frame in submission order.
+    pub fn quads(&self) -> impl Iterator<Item = FrameQuad> + '_ {
+        self.frame.quads(&self.cache)
+    }
+}
+
+/// Glyphs positioned for a single frame. This is shared by the renderers so
+/// that they agree on glyph placement, cache keys and quad generation.
+#[derive(Default)]
+pub(crate) struct FrameGlyphs {
+    glyphs: Vec<RenderGlyph>,
+    runs: Vec<RenderRun>,
+    solids: Vec<SolidRect>,
+    /// Whether glyphs added to the frame are hinted.
+    pub hint: bool,
+    /// Antialiasing of monochrome glyphs added to the frame.
+    pub antialias: Antialias,
+}
+
+/// Screen space quad for a cached glyph.
+pub(crate) struct GlyphQuad {
+    pub rect: [f32; 4],
+    pub uv: [f32; 4],
+    pub color: [u8; 4],
+    pub format: Format,
+    pub page: u16,
+}
+
+/// Screen space quad drawn for a frame.
+pub(crate) enum FrameQuad {
+    Glyph(GlyphQuad),
+    /// Rectangle filled with a solid color, such as a decoration line.
+    Solid {
+        rect: [f32; 4],
+        color: [u8; 4],
+    },
+}
+
+impl FrameGlyphs {
+    pub fn clear(&mut self) {
+        self.glyphs.clear();
+        self.runs.clear();
+        self.solids.clear();
+    }
+
+    /// Returns the number of quads in the frame.
+    #[cfg(feature = "metal")]
+    pub fn len(&self) -> usize {
+        self.glyphs.len() + self.solids.len()
+    }
+
+    /// Positions the glyphs of the text and marks the cached ones as used in
+    /// the current frame. Returns true if any glyph is missing from the
+    /// cache.
+    pub fn add_text<S: AtlasStorage>(
+        &mut self,
+        x: f32,
+        y: f32,
+        text: &Text,
+        scale_ctx: &mut ScaleContext,
+        glyph_cache: &mut GlyphCache<S>,
+    ) -> bool {
+        let mut cache_miss = false;
+        for line in &text.lines {
+            let baseline = y + line.y + line.ascent;
+            let mut pen_x = x + line.x;
+            // Underlines and overlines are drawn beneath the glyphs of the
+            // line and strikethroughs over them.
+            for segment in &line.decorations {
+                if segment.kind != DecorationKind::Strikethrough {
+                    self.add_decoration(x + line.x, baseline, segment);
+                }
+            }
+            for run in &line.runs {
+                let scaler = scale_ctx.builder(run.font.as_ref()).build();
+                let is_color = scaler.has_color_outlines() || scaler.has_color_bitmaps();
+                let color = to_rgba8(run.color);
+                let start = self.glyphs.len();
+                let render_run = RenderRun {
+                    font: run.font.clone(),
+                    font_size: run.font_size,
+                    hint: self.hint,
+                    antialias: self.antialias,
+                    synthesis: run.synthesis,
+                    is_color,
+                    color,
+                    glyphs: start..start,
+                };
+                for glyph in &run.glyphs {
+                    let glyph_x = pen_x + glyph.x;
+                    let subpx = SubpixelOffset::quantize(glyph_x);
+                    self.glyphs.push(RenderGlyph {
+                        id: glyph.id,
+                        x: (glyph_x + 0.125).floor(),
+                        y: baseline - glyph.y,
+                        subpx,
+                    });
+                    // Touching every glyph keeps the ones used in this frame
+                    // from being evicted while missing glyphs are inserted.
+                    if glyph_cache
+                        .touch(&render_run.key(glyph.id, subpx))
+                        .is_none()
+                    {
+                        cache_miss = true;
+                    }
+                    pen_x += glyph.advance;
+                }
+                let end = self.glyphs.len();
+                self.runs.push(RenderRun {
+                    glyphs: start..end,
+                    ..render_run
+                });
+            }
+            for segment in &line.decorations {
+                if segment.kind == DecorationKind::Strikethrough {
+                    self.add_decoration(x + line.x, baseline, segment);
+                }
+            }
+        }
+        cache_miss
+    }
+
+    /// Adds solid rectangles drawing a decoration segment of a line that
+    /// starts at `x`. Rectangles are snapped to whole pixels so that lines
+    /// stay sharp.
+    fn add_decoration(&mut self, x: f32, baseline: f32, segment: &DecorationSegment) {
+        let color = to_rgba8(segment.color);
+        let before_run = self.runs.len();
+        let mut push = |rect: [f32; 4]| {
+            self.solids.push(SolidRect {
+                rect,
+                color,
+                before_run,
+            })
+        };
+        let thickness = segment.thickness.round().max(1.);
+        let center = baseline - segment.offset + segment.thickness * 0.5;
+        let top = (center - thickness * 0.5).round();
+        let x0 = (x + segment.x).round();
+        let x1 = (x + segment.x + segment.width).round();
+        match segment.style {
+            DecorationStyle::Solid => push([x0, top, x1, top + thickness]),
+            DecorationStyle::Double => {
+                let top = (center - thickness * 1.5).round();
+                push([x0, top, x1, top + thickness]);
+                push([x0, top + thickness * 2., x1, top + thickness * 3.]);
+            }
+            DecorationStyle::Dotted => {
+                let mut dot_x = x0;
+                while dot_x < x1 {
+                    push([dot_x, top, (dot_x + thickness).min(x1), top + thickness]);
+                    dot_x += thickness * 2.;
+                }
+            }
+            DecorationStyle::Wavy => {
+                // One column per pixel, covering the wave between adjacent
+                // pixel edges. The phase depends on the absolute position
+                // so that adjacent segments join up.
+                let amplitude = thickness;
+                let period = thickness * 6.;
+                let wave = |x: f32| center + amplitude * (x * std::f32::consts::TAU / period).sin();
+                let mut col_x = x0;
+                while col_x < x1 {
+                    let (a, b) = (wave(col_x), wave(col_x + 1.));
+                    let col_top = (a.min(b) - thickness * 0.5).round();
+                    let col_bottom = (a.max(b) + thickness * 0.5).round().max(col_top + 1.);
+                    push([col_x, col_top, col_x + 1., col_bottom]);
+                    col_x += 1.;
+                }
+            }
+        }
+    }
+
+    /// Inserts the glyphs that are missing from the cache and returns them
+    /// grouped by format and atlas page, ready to be rasterized. The
+    /// returned glyphs reference font data owned by the frame and must not
+    /// outlive it.
+    pub fn update_cache<S: AtlasStorage>(
+        &self,
+        scale_ctx: &mut ScaleContext,
+        glyph_cache: &mut GlyphCache<S>,
+    ) -> BTreeMap<(Format, u16), Vec<Glyph>> {
+        let mut outline = Outline::new();
+        let mut image = Image::new();
+        // Glyphs are collected per format and page since the rasterizer
+        // targets a single atlas texture at a time.
+        let mut batches: BTreeMap<(Format, u16), Vec<Glyph>> = BTreeMap::new();
+        for run in &self.runs {
+            let mut scaler = scale_ctx
+                .builder(run.font.as_ref())
+                .size(run.font_size)
+                .hint(run.hint)
+                .variations(run.font.variations.iter().map(|var| (var.tag, var.value)))
+                .build();
+            for glyph in &self.glyphs[run.glyphs.clone()] {
+                let key = run.key(glyph.id, glyph.subpx);
+                if glyph_cache.get(&key).is_some() {
+                    continue;
+                }
+                let (format, transform, placement) =
+                    match glyph_placement(&mut scaler, run, glyph.id, &mut outline, &mut image) {
+                        Some(placement) => placement,
+                        None => continue,
+                    };
+                if let Some(entry) =
+                    glyph_cache.insert(key, format, placement.width as u16, placement.height as u16)
+                {
+                    entry.left = placement.left as i16;
+                    entry.top = placement.top as i16;
+                    let font = run.font.as_ref();
+                    let rect = [
+                        (entry.uv[0] * ATLAS_SIZE as f32) as u16,
+                        (entry.uv[1] * ATLAS_SIZE as f32) as u16,
+                        placement.width as u16,
+                        placement.height as u16,
+                    ];
+                    let glyph = Glyph {
+                        unique_id: font.key.value(),
+                        font_data: font.data.as_ptr(),
+                        font_data_len: font.data.len() as _,
+                        font_offset: font.offset,
+                        font_size: run.font_size,
+                        glyph_id: glyph.id,
+                        transform: [
+                            transform.xx,
+                            transform.xy,
+                            transform.yx,
+                            transform.yy,
+                            transform.x,
+                            transform.y,
+                        ],
+                        rect,
+                        subpx: glyph.subpx.to_f32(),
+                        hint: run.hint,
+                        embolden: run.embolden_strength(format),
+                        variations: run.font.variations.as_ptr(),
+                        num_variations: run.font.variations.len() as _,
+                    };
+                    batches.entry((format, entry.page)).or_default().push(glyph);
+                }
+            }
+        }
+        batches
+    }
+
+    /// Returns the quads for the cached glyphs and decorations of the frame
+    /// in submission order.
+    pub fn quads<'a, S: AtlasStorage>(
+        &'a self,
+        glyph_cache: &'a GlyphCache<S>,
+    ) -> impl Iterator<Item = FrameQuad> + 'a {
+        // Solid rectangles are drawn before the run they were added before,
+        // and any remaining ones after the last run.
+        (0..=self.runs.len()).flat_map(move |index| {
+            let start = self
+                .solids
+                .partition_point(|solid| solid.before_run < index);
+            let end = self
+                .solids
+                .partition_point(|solid| solid.before_run <= index);
+            let solids = self.solids[start..end]
+                .iter()
+                .map(|solid| FrameQuad::Solid {
+                    rect: solid.rect,
+                    color: solid.color,
+                });
+            let glyphs = self
+                .runs
+                .get(index)
+                .into_iter()
+                .flat_map(move |run| self.run_quads(run, glyph_cache));
+            solids.chain(glyphs)
+        })
+    }
+
+    fn run_quads<'a, S: AtlasStorage>(
+        &'a self,
+        run: &'a RenderRun,
+        glyph_cache: &'a GlyphCache<S>,
+    ) -> impl Iterator<Item = FrameQuad> + 'a {
+        self.glyphs[run.glyphs.clone()]
+            .iter()
+            .filter_map(move |glyph| {
+                let entry = glyph_cache.get(&run.key(glyph.id, glyph.subpx))?;
+                if entry.width == 0 || entry.height == 0 {
+                    return None;
+                }
+                let x0 = glyph.x + entry.left as f32;
+                let y0 = glyph.y - entry.top as f32;
+                let x1 = x0 + entry.width as f32;
+                let y1 = y0 + entry.height as f32;
+                // Color glyphs keep their own colors; only the run opacity
+                // applies.
+                let color = if entry.format == Format::Rgba8 {
+                    [255, 255, 255, run.color[3]]
+                } else {
+                    run.color
+                };
+                Some(FrameQuad::Glyph(GlyphQuad {
+                    rect: [x0, y0, x1, y1],
+                    uv: entry.uv,
+                    color,
+                    format: entry.format,
+                    page: entry.page,
+                }))
+            })
+    }
+}
+
+/// Computes the format and atlas placement for a glyph in a run along with
+/// the full transform that moves the glyph into that placement, as described
+/// for `Glyph::transform`. Glyphs in color runs prefer color outlines and
+/// color bitmaps and fall back to the monochrome outline, which is stored in
+/// the mask format of the run.
+fn glyph_placement(
+    scaler: &mut Scaler,
+    run: &RenderRun,
+    id: u16,
+    outline: &mut Outline,
+    image: &mut Image,
+) -> Option<(Format, Transform, Placement)> {
+    let transform = run.synthesis.transform();
+    if run.is_color {
+        if scaler.scale_color_outline_into(id, outline) {
+            outline.transform(&transform);
+            // swash sizes the rendered image to the first layer.
+            if let Some(layer) = outline.get(0) {
+                let (offset, placement) =
+                    Placement::compute(Origin::BottomLeft, (0, 0), &layer.bounds());
+                return Some((
+                    Format::Rgba8,
+                    transform.then_translate(offset.x, offset.y),
+                    placement,
+                ));
+            }
+        }
+        if scaler.scale_color_bitmap_into(id, StrikeWith::BestFit, image) {
+            // Bitmaps are not transformed, so only move the bottom left
+            // corner of the image to the origin.
+            let placement = image.placement;
+            let offset = Transform::translation(
+                -placement.left as f32,
+                placement.height as f32 - placement.top as f32,
+            );
+            return Some((Format::Rgba8, offset, placement));
+        }
+    }
+    if !scaler.scale_outline_into(id, outline) {
+        return None;
+    }
+    let mask_format = run.antialias.format();
+    let strength = run.embolden_strength(mask_format);
+    if strength != 0. {
+        outline.embolden(strength, strength);
+    }
+    outline.transform(&transform);
+    let (offset, placement) = Placement::compute(Origin::BottomLeft, (0, 0), &outline.bounds());
+    Some((
+        mask_format,
+        transform.then_translate(offset.x, offset.y),
+        placement,
+    ))
+}
+
+struct RenderRun {
+    font: Font,
+    font_size: f32,
+    hint: bool,
+    antialias: Antialias,
+    synthesis: Synthesis,
+    is_color: bool,
+    color: [u8; 4],
+    glyphs: Range<usize>,
+}
+
+impl RenderRun {
+    /// Returns the emboldening strength for glyphs of the run stored in the
+    /// given format. Color glyphs are never emboldened.
+    fn embolden_strength(&self, format: Format) -> f32 {
+        if format == Format::Rgba8 {
+            0.
+        } else {
+            self.synthesis.embolden_strength(self.font_size)
+        }
+    }
+
+    fn key(&self, id: u16, subpx: SubpixelOffset) -> GlyphKey {
+        GlyphKey {
+            font_id: self.font.key,
+            variations: self.font.variations_key,
+            font_size: self.font_size.to_bits(),
+            subpx,
+            hint: self.hint,
+            antialias: self.antialias,
+            synthesis: self.synthesis,
+            id,
+        }
+    }
+}
+
+/// Solid rectangle drawn before the run at `before_run`.
+struct SolidRect {
+    rect: [f32; 4],
+    color: [u8; 4],
+    before_run: usize,
+}
+
+fn to_rgba8(color: [f32; 4]) -> [u8; 4] {
+    color.map(|c| (c * 255.) as u8)
+}
+
+struct RenderGlyph {
+    id: u16,
+    x: f32,
+    y: f32,
+    subpx: SubpixelOffset,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glyph_cache::CpuAtlasStorage;
+    use crate::glyph_rasterizer::SoftwareGlyphRasterizer;
+    use crate::text::TextBuilder;
+
+    const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+
+    fn font() -> Font {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fonts/DejaVuSans.ttf");
+        Font::from_file(path).expect("failed to load bundled font")
+    }
+
+    /// Draws the texts in a new frame and returns the ids of the glyphs
+    /// that were rasterized.
+    fn draw(
+        glyphs: &mut GlyphContext<CpuAtlasStorage>,
+        rasterizer: &mut SoftwareGlyphRasterizer,
+        texts: &[&Text],
+    ) -> Vec<u16> {
+        glyphs.begin_frame();
+        for text in texts {
+            glyphs.add_text(0., 0., text);
+        }
+        let mut rasterized = vec![];
+        glyphs.update_cache(|format, texture, batch| {
+            rasterizer.begin(format, ATLAS_SIZE, ATLAS_SIZE);
+            for glyph in batch {
+                rasterized.push(glyph.glyph_id);
+                // The font data is owned by the runs for this frame.
+                unsafe {
+                    rasterizer.add_glyph(glyph);
+                }
+            }
+            rasterizer.write_to(texture);
+        });
+        rasterized.sort_unstable();
+        rasterized
+    }
+
+    /// Returns the page, texture coordinates and texels of the cache entry
+    /// of each glyph in the frame.
+    fn entries(glyphs: &GlyphContext<CpuAtlasStorage>) -> Vec<(u16, [f32; 4], Vec<u8>)> {
+        let frame = &glyphs.frame;
+        let mut entries = vec![];
+        for run in &frame.runs {
+            for glyph in &frame.glyphs[run.glyphs.clone()] {
+                let entry = glyphs.cache.get(&run.key(glyph.id, glyph.subpx)).unwrap();
+                let texture = &glyphs.atlas(entry.format, entry.page).unwrap().texture;
+                let x = (entry.uv[0] * ATLAS_SIZE as f32).round() as usize;
+                let y = (entry.uv[1] * ATLAS_SIZE as f32).round() as usize;
+                let pixel_size = entry.format.pixel_size();
+                let row_len = entry.width as usize * pixel_size;
+                let texels = (y..y + entry.height as usize)
+                    .flat_map(|row| {
+                        let offset = row * texture.stride() + x * pixel_size;
+                        texture.data[offset..offset + row_len].iter().copied()
+                    })
+                    .collect();
+                entries.push((entry.page, entry.uv, texels));
+            }
+        }
+        entries
+    }
+
+    #[test]
+    fn incremental_update_keeps_existing_glyphs() {
+        let font = font();
+        let build = |text| {
+            TextBuilder::new(None)
+                .add_text(&font, 16.0, BLACK, text)
+                .build()
+        };
+        let (first, second) = (build("abc"), build("xyz"));
+        let ids = |text: &Text| {
+            let mut ids: Vec<u16> = text.lines[0]
+                .runs
+                .iter()
+                .flat_map(|run| run.glyphs.iter().map(|glyph| glyph.id))
+                .collect();
+            ids.sort_unstable();
+            ids
+        };
+        let mut glyphs = GlyphContext::new(CpuAtlasStorage);
+        let mut rasterizer = SoftwareGlyphRasterizer::new();
+        assert_eq!(draw(&mut glyphs, &mut rasterizer, &[&first]), ids(&first));
+        let before = entries(&glyphs);
+        assert!(before
+            .iter()
+            .all(|(.., texels)| texels.iter().any(|t| *t != 0)));
+        // Only the glyphs of the new text are rasterized and the entries and
+        // texels of the cached ones are left as they were.
+        assert_eq!(
+            draw(&mut glyphs, &mut rasterizer, &[&first, &second]),
+            ids(&second)
+        );
+        let after = entries(&glyphs);
+        assert_eq!(after.len(), before.len() * 2);
+        assert_eq!(after[..before.len()], before[..]);
+        // Nothing is rasterized when every glyph is cached.
+        assert!(draw(&mut glyphs, &mut rasterizer, &[&second, &first]).is_empty());
+        let mut again = entries(&glyphs);
+        again.rotate_left(before.len());
+        assert_eq!(again, after);
+    }
+}
@@ -1,4 +1,39 @@
-use swash::{FontDataRef, FontRef};
+use swash::zeno::{Angle, Transform};
+use swash::FontRef;
+
+/// Slant of synthetic oblique glyphs in degrees.
+pub(crate) const OBLIQUE_ANGLE: f32 = 14.0;
+
+/// Synthetic styles for fonts that lack a bold or italic face.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug)]
+pub struct Synthesis {
+    /// Thickens outlines and widens advances to match.
+    pub embolden: bool,
+    /// Slants outlines to the right.
+    pub oblique: bool,
+}
+
+impl Synthesis {
+    /// Returns the distance in pixels that emboldening moves each edge of
+    /// an outline at the given font size. Glyphs grow by twice this amount
+    /// to the right and to the top.
+    pub fn embolden_strength(&self, font_size: f32) -> f32 {
+        if self.embolden {
+            font_size / 48.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the transform applied to outlines in glyph space.
+    pub fn transform(&self) -> Transform {
+        if self.oblique {
+            Transform::skew(Angle::from_degrees(OBLIQUE_ANGLE), Angle::ZERO)
+        } else {
+            Transform::IDENTITY
+        }
+    }
+}
 
 #[derive(Copy, Clone)]
 #[repr(C)]
@@ -13,11 +48,29 @@ pub struct Glyph {
     pub unique_id: u64,
     pub font_data: *const u8,
     pub font_data_len: u32,
+    /// Offset to the table directory of the face in the font data.
+    pub font_offset: u32,
     pub font_size: f32,
     pub glyph_id: u16,
+    /// Affine transform `[xx, xy, yx, yy, x, y]` from glyph space to the
+    /// rectangle of the glyph in the atlas.
+    ///
+    /// Glyph space is the outline scaled to `font_size` pixels per em with
+    /// the glyph origin at (0, 0) and y pointing up. The target space is in
+    /// pixels from the bottom left corner of `rect`, also with y pointing up.
+    /// A point `(px, py)` maps to `(xx * px + yx * py + x, xy * px + yy * py + y)`,
+    /// as with `zeno::Transform`. Bitmap glyphs are only translated.
     pub transform: [f32; 6],
+    /// Rectangle of the glyph in the atlas as `[x, y, width, height]`.
     pub rect: [u16; 4],
+    /// Horizontal offset in pixels added after the transform.
     pub subpx: f32,
+    /// Whether the outline is hinted at the font size before it is
+    /// transformed.
+    pub hint: bool,
+    /// Distance in pixels that synthetic emboldening moves the edges of the
+    /// outline before it is transformed. Zero leaves the outline unchanged.
+    pub embolden: f32,
     pub variations: *const FontVariation,
     pub num_variations: u32,
 }
@@ -26,8 +79,8 @@ impl Glyph {
     pub fn font_ref(&self) -> FontRef {
         unsafe {
             let data = std::slice::from_raw_parts(self.font_data, self.font_data_len as usize);
-            let font_data = FontDataRef::new(data).expect("invalid font");
-            let mut font_ref = font_data.get(0).expect("invalid font index");
+            let mut font_ref =
+                FontRef::from_offset(data, self.font_offset).expect("invalid font offset");
             font_ref.key = std::mem::transmute(self.unique_id);
             font_ref
         }
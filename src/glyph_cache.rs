@@ -1,120 +1,413 @@
-use metal::{Device, Texture};
+use super::glyph::Synthesis;
+use super::glyph_rasterizer::Format;
 use std::collections::HashMap;
 use swash::CacheKey;
 
 pub const ATLAS_SIZE: u32 = 1024;
 
-pub struct Atlas {
-    pub texture: Texture,
+/// Backing storage for the textures of atlas pages.
+pub trait AtlasStorage {
+    type Texture;
+    fn new_texture(&mut self, format: Format, width: u32, height: u32) -> Self::Texture;
+}
+
+/// Atlas storage backed by Metal textures.
+#[cfg(feature = "metal")]
+pub struct MetalAtlasStorage {
+    device: metal::Device,
+}
+
+#[cfg(feature = "metal")]
+impl MetalAtlasStorage {
+    pub fn new(device: metal::Device) -> Self {
+        Self { device }
+    }
+}
+
+#[cfg(feature = "metal")]
+impl AtlasStorage for MetalAtlasStorage {
+    type Texture = metal::Texture;
+
+    fn new_texture(&mut self, format: Format, width: u32, height: u32) -> Self::Texture {
+        let format = match format {
+            Format::A8 => metal::MTLPixelFormat::A8Unorm,
+            _ => metal::MTLPixelFormat::RGBA8Unorm,
+        };
+        let desc = metal::TextureDescriptor::new();
+        desc.set_width(width as _);
+        desc.set_height(height as _);
+        desc.set_pixel_format(format);
+        desc.set_usage(metal::MTLTextureUsage::ShaderRead | metal::MTLTextureUsage::ShaderWrite);
+        self.device.new_texture(&desc)
+    }
+}
+
+/// Atlas storage backed by buffers in system memory.
+#[derive(Copy, Clone, Default)]
+pub struct CpuAtlasStorage;
+
+impl AtlasStorage for CpuAtlasStorage {
+    type Texture = CpuTexture;
+
+    fn new_texture(&mut self, format: Format, width: u32, height: u32) -> Self::Texture {
+        CpuTexture::new(format, width, height)
+    }
+}
+
+/// Texture stored in system memory with rows of tightly packed pixels.
+#[derive(Clone)]
+pub struct CpuTexture {
+    pub format: Format,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl CpuTexture {
+    pub fn new(format: Format, width: u32, height: u32) -> Self {
+        let size = width as usize * height as usize * format.pixel_size();
+        Self {
+            format,
+            width,
+            height,
+            data: vec![0; size],
+        }
+    }
+
+    /// Returns the number of bytes in a row of the texture.
+    pub fn stride(&self) -> usize {
+        self.width as usize * self.format.pixel_size()
+    }
+
+    /// Replaces the pixels in the rectangle with rows of the source data that
+    /// are `stride` bytes apart. The rectangle is clipped to the texture.
+    pub fn replace_region(&mut self, rect: [u16; 4], data: &[u8], stride: usize) {
+        let pixel_size = self.format.pixel_size();
+        let [x, y, width, height] = rect;
+        let x = (x as usize).min(self.width as usize);
+        let width = (width as usize).min(self.width as usize - x);
+        let height = (height as usize).min((self.height as usize).saturating_sub(y as usize));
+        let row_size = width * pixel_size;
+        let target_stride = self.stride();
+        for row in 0..height {
+            let src = match data.get(row * stride..row * stride + row_size) {
+                Some(src) => src,
+                None => break,
+            };
+            let offset = (y as usize + row) * target_stride + x * pixel_size;
+            self.data[offset..offset + row_size].copy_from_slice(src);
+        }
+    }
+}
+
+pub struct Atlas<T> {
+    pub texture: T,
     pub allocator: etagere::AtlasAllocator,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct GlyphKey {
     pub font_id: CacheKey,
+    /// Hash of the variation settings for the font instance.
+    pub variations: u64,
     pub font_size: u32,
     pub subpx: SubpixelOffset,
+    pub hint: bool,
+    pub antialias: Antialias,
+    pub synthesis: Synthesis,
     pub id: u16,
 }
 
 #[derive(Copy, Clone)]
 pub struct GlyphEntry {
-    pub is_color: bool,
+    /// Format of the glyph image. Alpha masks are stored in the alpha pages
+    /// and all other formats in the color pages.
+    pub format: Format,
+    /// Index of the atlas page in the alpha or color pages.
+    pub page: u16,
     pub uv: [f32; 4],
     pub left: i16,
     pub top: i16,
     pub width: u16,
     pub height: u16,
+    alloc_id: Option<etagere::AllocId>,
+    /// Frame in which the glyph was last used.
+    frame: u64,
+}
+
+impl GlyphEntry {
+    /// Returns true if the glyph is stored in the color pages.
+    pub fn is_color_page(&self) -> bool {
+        self.format != Format::A8
+    }
 }
 
-pub struct GlyphCache {
-    device: Device,
-    pub alpha: Option<Atlas>,
-    pub color: Option<Atlas>,
+fn size_in_bytes(format: Format, width: u16, height: u16) -> usize {
+    width as usize * height as usize * format.pixel_size()
+}
+
+/// Cache of glyph locations in atlas pages. The cache handles allocation,
+/// keying and eviction while the texture storage is provided by the
+/// backend.
+pub struct GlyphCache<S: AtlasStorage> {
+    storage: S,
+    pub alpha: Vec<Atlas<S::Texture>>,
+    pub color: Vec<Atlas<S::Texture>>,
     pub map: HashMap<GlyphKey, GlyphEntry>,
-    // TODO: variations
+    frame: u64,
+    budget: usize,
+    memory: usize,
+    max_pages: usize,
 }
 
-impl GlyphCache {
-    pub fn new(device: Device) -> Self {
+impl<S: AtlasStorage> GlyphCache<S> {
+    pub fn new(storage: S) -> Self {
         Self {
-            device,
-            alpha: None,
-            color: None,
+            storage,
+            alpha: vec![],
+            color: vec![],
             map: Default::default(),
+            frame: 0,
+            budget: usize::MAX,
+            memory: 0,
+            max_pages: usize::MAX,
         }
     }
 
+    /// Sets the number of bytes of atlas space that glyphs may occupy before
+    /// least recently used glyphs are evicted. By default, the budget is
+    /// unlimited and glyphs are only evicted when no page has room for them.
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+    }
+
+    /// Sets the maximum number of atlas pages for each of the alpha and
+    /// color formats. By default, new pages are added whenever the existing
+    /// ones are full.
+    pub fn set_max_pages(&mut self, max_pages: usize) {
+        self.max_pages = max_pages.max(1);
+    }
+
+    /// Returns the number of bytes of atlas space occupied by glyphs.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    /// Starts a new frame. Glyphs used during the frame are protected from
+    /// eviction until the next call.
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+    }
+
     pub fn get(&self, key: &GlyphKey) -> Option<&GlyphEntry> {
         self.map.get(key)
     }
 
+    /// Returns the entry for the key and marks it as used in the current
+    /// frame.
+    pub fn touch(&mut self, key: &GlyphKey) -> Option<&GlyphEntry> {
+        let frame = self.frame;
+        let entry = self.map.get_mut(key)?;
+        entry.frame = frame;
+        Some(entry)
+    }
+
     pub fn insert(
         &mut self,
         key: GlyphKey,
-        is_color: bool,
+        format: Format,
         width: u16,
         height: u16,
     ) -> Option<&mut GlyphEntry> {
-        let (atlas, format) = if is_color {
-            (&mut self.color, metal::MTLPixelFormat::RGBA8Unorm)
-        } else {
-            (&mut self.alpha, metal::MTLPixelFormat::A8Unorm)
-        };
-        if atlas.is_none() {
-            let desc = metal::TextureDescriptor::new();
-            desc.set_width(ATLAS_SIZE as _);
-            desc.set_height(ATLAS_SIZE as _);
-            desc.set_pixel_format(format);
-            desc.set_usage(
-                metal::MTLTextureUsage::ShaderRead | metal::MTLTextureUsage::ShaderWrite,
-            );
-            let texture = self.device.new_texture(&desc);
-            *atlas = Some(Atlas {
-                texture,
-                allocator: etagere::AtlasAllocator::new(etagere::size2(
-                    ATLAS_SIZE as i32,
-                    ATLAS_SIZE as i32,
-                )),
-            });
+        // Glyphs larger than a page can never be allocated, so don't evict
+        // anything for them.
+        if width as u32 > ATLAS_SIZE || height as u32 > ATLAS_SIZE {
+            return None;
         }
+        // Inserting a cached key replaces its entry, so free the old space.
+        self.remove(&key);
         let entry = if width == 0 || height == 0 {
             GlyphEntry {
-                is_color,
+                format,
+                page: 0,
                 uv: [0.0; 4],
                 left: 0,
                 top: 0,
                 width,
                 height,
+                alloc_id: None,
+                frame: self.frame,
             }
         } else {
-            let atlas = atlas.as_mut()?;
-            let allocation = atlas
-                .allocator
-                .allocate(etagere::size2(width as i32, height as i32))?;
+            let size = size_in_bytes(format, width, height);
+            let is_color = format != Format::A8;
+            // Collected at most once per insert, when eviction is needed.
+            let mut candidates = None;
+            if self.memory + size > self.budget {
+                let candidates = candidates.get_or_insert_with(|| self.eviction_candidates());
+                self.evict(candidates, None, self.memory + size - self.budget);
+            }
+            let (page, allocation) = loop {
+                if let Some(allocation) = self.allocate(is_color, width, height) {
+                    break allocation;
+                }
+                // A new page is added whenever the limit allows, so all
+                // pages are full or too fragmented. Free up space in them
+                // and try again.
+                let candidates = candidates.get_or_insert_with(|| self.eviction_candidates());
+                if !self.evict(candidates, Some(is_color), size) {
+                    return None;
+                }
+            };
+            self.memory += size;
             let rect = allocation.rectangle;
             let x0 = rect.min.x as f32 / ATLAS_SIZE as f32;
             let y0 = rect.min.y as f32 / ATLAS_SIZE as f32;
             let x1 = (rect.min.x as f32 + width as f32) / ATLAS_SIZE as f32;
             let y1 = (rect.min.y as f32 + height as f32) / ATLAS_SIZE as f32;
             GlyphEntry {
-                is_color,
+                format,
+                page: page as u16,
                 uv: [x0, y0, x1, y1],
                 left: 0,
                 top: 0,
                 width,
                 height,
+                alloc_id: Some(allocation.id),
+                frame: self.frame,
             }
         };
         self.map.insert(key, entry);
         self.map.get_mut(&key)
     }
 
+    /// Allocates space in the first alpha or color page with room for the
+    /// glyph, adding a new page if none has room and the page limit allows
+    /// it.
+    fn allocate(
+        &mut self,
+        is_color: bool,
+        width: u16,
+        height: u16,
+    ) -> Option<(usize, etagere::Allocation)> {
+        let size = etagere::size2(width as i32, height as i32);
+        let (pages, format) = if is_color {
+            (&mut self.color, Format::Rgba8)
+        } else {
+            (&mut self.alpha, Format::A8)
+        };
+        for (index, atlas) in pages.iter_mut().enumerate() {
+            if let Some(allocation) = atlas.allocator.allocate(size) {
+                return Some((index, allocation));
+            }
+        }
+        if pages.len() >= self.max_pages {
+            return None;
+        }
+        let mut allocator =
+            etagere::AtlasAllocator::new(etagere::size2(ATLAS_SIZE as i32, ATLAS_SIZE as i32));
+        // Glyphs larger than a page can never be allocated.
+        let allocation = allocator.allocate(size)?;
+        let texture = self.storage.new_texture(format, ATLAS_SIZE, ATLAS_SIZE);
+        pages.push(Atlas { texture, allocator });
+        Some((pages.len() - 1, allocation))
+    }
+
     pub fn clear(&mut self) {
         for atlas in self.alpha.iter_mut().chain(self.color.iter_mut()) {
             atlas.allocator.clear();
         }
         self.map.clear();
+        self.memory = 0;
+    }
+
+    /// Returns the glyphs that may be evicted. Glyphs used in the current
+    /// frame are never evicted.
+    fn eviction_candidates(&self) -> EvictionCandidates {
+        let mut candidates = EvictionCandidates::default();
+        for (key, entry) in &self.map {
+            if entry.frame == self.frame || entry.alloc_id.is_none() {
+                continue;
+            }
+            let keys = if entry.is_color_page() {
+                &mut candidates.color
+            } else {
+                &mut candidates.alpha
+            };
+            keys.push((entry.frame, *key));
+        }
+        // Least recently used glyphs are popped first.
+        for keys in [&mut candidates.alpha, &mut candidates.color] {
+            keys.sort_unstable_by_key(|(frame, _)| std::cmp::Reverse(*frame));
+        }
+        candidates
+    }
+
+    /// Evicts least recently used candidates until at least `bytes` of
+    /// atlas space has been freed, optionally restricted to the color or
+    /// alpha pages. Returns false if nothing could be evicted.
+    fn evict(
+        &mut self,
+        candidates: &mut EvictionCandidates,
+        is_color: Option<bool>,
+        bytes: usize,
+    ) -> bool {
+        let mut freed = 0;
+        let mut evicted = false;
+        while freed < bytes {
+            let key = match candidates.pop(is_color) {
+                Some(key) => key,
+                None => break,
+            };
+            if let Some(size) = self.remove(&key) {
+                freed += size;
+                evicted = true;
+            }
+        }
+        evicted
+    }
+
+    /// Removes the entry for the key and frees its atlas space. Returns the
+    /// number of bytes freed.
+    fn remove(&mut self, key: &GlyphKey) -> Option<usize> {
+        let entry = self.map.remove(key)?;
+        let pages = if entry.is_color_page() {
+            &mut self.color
+        } else {
+            &mut self.alpha
+        };
+        let atlas = pages.get_mut(entry.page as usize);
+        if let (Some(atlas), Some(id)) = (atlas, entry.alloc_id) {
+            atlas.allocator.deallocate(id);
+        }
+        let size = size_in_bytes(entry.format, entry.width, entry.height);
+        self.memory -= size;
+        Some(size)
+    }
+}
+
+/// Keys of glyphs that may be evicted for each kind of page, sorted so that
+/// the least recently used glyph is last.
+#[derive(Default)]
+struct EvictionCandidates {
+    alpha: Vec<(u64, GlyphKey)>,
+    color: Vec<(u64, GlyphKey)>,
+}
+
+impl EvictionCandidates {
+    /// Removes the least recently used glyph, optionally restricted to the
+    /// color or alpha pages.
+    fn pop(&mut self, is_color: Option<bool>) -> Option<GlyphKey> {
+        let keys = match is_color {
+            Some(true) => &mut self.color,
+            Some(false) => &mut self.alpha,
+            None => match (self.alpha.last(), self.color.last()) {
+                (Some(alpha), Some(color)) if color.0 < alpha.0 => &mut self.color,
+                (None, _) => &mut self.color,
+                _ => &mut self.alpha,
+            },
+        };
+        keys.pop().map(|(_, key)| key)
     }
 }
 
@@ -147,3 +440,209 @@ impl SubpixelOffset {
         }
     }
 }
+
+/// Antialiasing of glyphs rendered from monochrome outlines.
+#[derive(Hash, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Antialias {
+    /// Single coverage value per pixel.
+    #[default]
+    Grayscale,
+    /// Coverage per color channel for displays with RGB subpixels.
+    SubpixelRgb,
+    /// Coverage per color channel for displays with BGR subpixels.
+    SubpixelBgr,
+}
+
+impl Antialias {
+    /// Returns the atlas format for monochrome glyphs.
+    pub fn format(self) -> Format {
+        match self {
+            Antialias::Grayscale => Format::A8,
+            Antialias::SubpixelRgb => Format::SubpixelRgb,
+            Antialias::SubpixelBgr => Format::SubpixelBgr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(font_id: CacheKey, id: u16) -> GlyphKey {
+        GlyphKey {
+            font_id,
+            variations: 0,
+            font_size: 16f32.to_bits(),
+            subpx: SubpixelOffset::Zero,
+            hint: false,
+            antialias: Antialias::Grayscale,
+            synthesis: Synthesis::default(),
+            id,
+        }
+    }
+
+    #[test]
+    fn key_fields_separate_entries() {
+        let mut cache = GlyphCache::new(CpuAtlasStorage);
+        let base = key(CacheKey::new(), 1);
+        let keys = [
+            base,
+            GlyphKey {
+                font_size: 17f32.to_bits(),
+                ..base
+            },
+            GlyphKey {
+                subpx: SubpixelOffset::Half,
+                ..base
+            },
+            GlyphKey { hint: true, ..base },
+            GlyphKey {
+                variations: 1,
+                ..base
+            },
+            GlyphKey {
+                synthesis: Synthesis {
+                    embolden: true,
+                    oblique: false,
+                },
+                ..base
+            },
+        ];
+        for key in &keys {
+            assert!(cache.insert(*key, Format::A8, 10, 10).is_some());
+        }
+        assert_eq!(cache.map.len(), keys.len());
+        let mut uvs: Vec<_> = keys
+            .iter()
+            .map(|key| cache.get(key).unwrap().uv.map(f32::to_bits))
+            .collect();
+        uvs.sort_unstable();
+        uvs.dedup();
+        assert_eq!(uvs.len(), keys.len());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_over_budget() {
+        let mut cache = GlyphCache::new(CpuAtlasStorage);
+        // Room for three 10x10 alpha glyphs.
+        cache.set_budget(300);
+        let font_id = CacheKey::new();
+        for id in 0..3 {
+            cache.begin_frame();
+            cache.insert(key(font_id, id), Format::A8, 10, 10);
+        }
+        cache.begin_frame();
+        assert!(cache.touch(&key(font_id, 0)).is_some());
+        cache.insert(key(font_id, 3), Format::A8, 10, 10);
+        assert!(cache.get(&key(font_id, 1)).is_none());
+        for id in [0, 2, 3] {
+            assert!(cache.get(&key(font_id, id)).is_some());
+        }
+        assert_eq!(cache.memory(), 300);
+    }
+
+    #[test]
+    fn never_evicts_glyphs_used_in_current_frame() {
+        let mut cache = GlyphCache::new(CpuAtlasStorage);
+        let font_id = CacheKey::new();
+        // The budget is exceeded, but every glyph is in use.
+        cache.set_budget(200);
+        cache.begin_frame();
+        for id in 0..3 {
+            assert!(cache.insert(key(font_id, id), Format::A8, 10, 10).is_some());
+        }
+        assert_eq!(cache.map.len(), 3);
+        // A full page at the page limit fails the insert instead.
+        cache.set_max_pages(1);
+        let size = ATLAS_SIZE as u16;
+        assert!(cache
+            .insert(key(font_id, 3), Format::A8, size, size)
+            .is_none());
+        assert_eq!(cache.map.len(), 3);
+    }
+
+    #[test]
+    fn spills_over_to_new_page() {
+        let mut cache = GlyphCache::new(CpuAtlasStorage);
+        let font_id = CacheKey::new();
+        let (width, height) = (ATLAS_SIZE as u16, ATLAS_SIZE as u16 / 2 + 1);
+        let first = cache
+            .insert(key(font_id, 0), Format::A8, width, height)
+            .map(|entry| entry.page);
+        cache.begin_frame();
+        // The first glyph is unused in this frame, but a new page is added
+        // rather than evicting it.
+        let second = cache
+            .insert(key(font_id, 1), Format::A8, width, height)
+            .map(|entry| entry.page);
+        assert_eq!((first, second), (Some(0), Some(1)));
+        assert_eq!(cache.alpha.len(), 2);
+        assert!(cache.get(&key(font_id, 0)).is_some());
+        // At the page limit, the least recently used glyph makes room.
+        cache.set_max_pages(2);
+        cache.begin_frame();
+        let third = cache
+            .insert(key(font_id, 2), Format::A8, width, height)
+            .map(|entry| entry.page);
+        assert_eq!(third, Some(0));
+        assert!(cache.get(&key(font_id, 0)).is_none());
+        assert!(cache.get(&key(font_id, 1)).is_some());
+    }
+
+    #[test]
+    fn separates_color_and_mask_pages() {
+        let mut cache = GlyphCache::new(CpuAtlasStorage);
+        let font_id = CacheKey::new();
+        let formats = [Format::A8, Format::Rgba8, Format::SubpixelRgb];
+        for (id, format) in formats.iter().enumerate() {
+            let entry = cache.insert(key(font_id, id as u16), *format, 10, 10);
+            let entry = entry.unwrap();
+            assert_eq!(entry.format, *format);
+            assert_eq!(entry.is_color_page(), *format != Format::A8);
+        }
+        assert_eq!(cache.alpha.len(), 1);
+        assert_eq!(cache.color.len(), 1);
+        assert_eq!(cache.alpha[0].texture.format, Format::A8);
+        assert_eq!(cache.color[0].texture.format, Format::Rgba8);
+        assert_eq!(cache.memory(), 100 + 400 + 400);
+    }
+
+    #[test]
+    fn oversized_glyph_leaves_cache_untouched() {
+        let mut cache = GlyphCache::new(CpuAtlasStorage);
+        let font_id = CacheKey::new();
+        for id in 0..100 {
+            assert!(cache.insert(key(font_id, id), Format::A8, 10, 10).is_some());
+        }
+        cache.begin_frame();
+        let memory = cache.memory();
+        let oversized = ATLAS_SIZE as u16 + 76;
+        assert!(cache
+            .insert(key(font_id, 100), Format::A8, oversized, 10)
+            .is_none());
+        assert!(cache
+            .insert(key(font_id, 101), Format::A8, 10, oversized)
+            .is_none());
+        assert_eq!(cache.map.len(), 100);
+        assert_eq!(cache.memory(), memory);
+        assert_eq!(cache.alpha.len(), 1);
+    }
+
+    #[test]
+    fn reinserting_key_replaces_entry() {
+        let mut cache = GlyphCache::new(CpuAtlasStorage);
+        cache.set_max_pages(1);
+        let glyph = key(CacheKey::new(), 0);
+        assert!(cache.insert(glyph, Format::A8, 10, 10).is_some());
+        assert!(cache.insert(glyph, Format::A8, 20, 20).is_some());
+        assert_eq!(cache.map.len(), 1);
+        assert_eq!(cache.memory(), 400);
+        // A glyph filling the page only fits again if the space of the
+        // previous entry was freed.
+        let size = ATLAS_SIZE as u16;
+        assert!(cache.insert(glyph, Format::A8, size, size).is_some());
+        assert!(cache.insert(glyph, Format::A8, size, size).is_some());
+        assert_eq!(cache.memory(), size as usize * size as usize);
+        assert_eq!(cache.alpha.len(), 1);
+    }
+}
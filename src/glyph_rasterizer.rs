@@ -1,18 +1,47 @@
 use super::glyph::Glyph;
+use super::glyph_cache::CpuTexture;
+#[cfg(feature = "metal")]
 use metal::*;
 use swash::scale::{image::Image, ScaleContext};
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub enum Format {
     A8,
     Rgba8,
+    /// Coverage for each color channel of displays with horizontal RGB
+    /// subpixels, stored in the color channels of an RGBA8 texture.
+    SubpixelRgb,
+    /// Coverage for each color channel of displays with horizontal BGR
+    /// subpixels. The channels are stored in the same order as
+    /// `SubpixelRgb`.
+    SubpixelBgr,
 }
 
+impl Format {
+    /// Returns the number of bytes in a pixel of the format.
+    pub fn pixel_size(self) -> usize {
+        match self {
+            Format::A8 => 1,
+            _ => 4,
+        }
+    }
+
+    /// Returns true for the subpixel coverage formats.
+    pub fn is_subpixel(self) -> bool {
+        matches!(self, Format::SubpixelRgb | Format::SubpixelBgr)
+    }
+}
+
+#[cfg(feature = "metal")]
 pub trait GlyphRasterizer {
     fn new(device: &DeviceRef, queue: &CommandQueueRef) -> Self;
+    /// Begins a batch of glyphs for an atlas with the given format and size.
     fn begin(&mut self, format: Format, width: u32, height: u32);
     // Unsafe because pointer in glyph must reference valid slice.
     unsafe fn add_glyph(&mut self, glyph: &Glyph);
+    /// Records commands that write the glyphs added since `begin` into the
+    /// target. Only the rectangles of those glyphs are written; all other
+    /// texels in the target are preserved.
     fn record(&mut self, cmdbuf: &CommandBufferRef, target: &TextureRef) -> u32;
     fn release(&mut self, id: u32);
 }
@@ -25,10 +54,12 @@ pub struct SoftwareGlyphRasterizer {
     width: usize,
     height: usize,
     stride: usize,
+    // Rectangles written since the last call to begin.
+    rects: Vec<[u16; 4]>,
 }
 
-impl GlyphRasterizer for SoftwareGlyphRasterizer {
-    fn new(_device: &DeviceRef, _queue: &CommandQueueRef) -> Self {
+impl SoftwareGlyphRasterizer {
+    pub fn new() -> Self {
         Self {
             scx: ScaleContext::new(),
             image: Image::new(),
@@ -37,155 +68,207 @@ impl GlyphRasterizer for SoftwareGlyphRasterizer {
             width: 0,
             height: 0,
             stride: 0,
+            rects: vec![],
         }
     }
 
-    fn begin(&mut self, format: Format, width: u32, height: u32) {
-        let pixel_size = match format {
-            Format::A8 => 1,
-            Format::Rgba8 => 4,
-        };
+    /// Begins a batch of glyphs for an atlas with the given format and size.
+    pub fn begin(&mut self, format: Format, width: u32, height: u32) {
         self.format = format;
         self.width = width as usize;
         self.height = height as usize;
-        self.stride = self.width * pixel_size;
+        self.stride = self.width * format.pixel_size();
+        // Each glyph clears its own rectangle, so the buffer only needs to be
+        // large enough.
         let size = self.stride * self.height;
-        self.pixbuf.clear();
         self.pixbuf.resize(size, 0);
+        self.rects.clear();
     }
 
-    unsafe fn add_glyph(&mut self, glyph: &Glyph) {
-        println!("adding glyph {:?}", glyph);
-        use swash::scale::{Render, Source};
-        use swash::zeno::{Transform, Vector};
+    /// Rasterizes a glyph into its rectangle in the pixel buffer.
+    ///
+    /// # Safety
+    ///
+    /// The font data and variation pointers in the glyph must reference valid
+    /// slices.
+    pub unsafe fn add_glyph(&mut self, glyph: &Glyph) {
+        use swash::scale::{image::Content, Render, Source, StrikeWith};
+        use swash::zeno::{self, Transform, Vector};
         let mut scaler = self
             .scx
             .builder(glyph.font_ref())
             .size(glyph.font_size)
+            .hint(glyph.hint)
             .variations(glyph.variations().iter().map(|var| (var.tag, var.value)))
             .build();
-        // Which components should be present here? piet-gpu and swash have
-        // different expectations
-        let _transform = Transform {
-            xx: glyph.transform[0],
-            xy: glyph.transform[1],
-            yx: glyph.transform[2],
-            yy: glyph.transform[3],
-            x: glyph.transform[4],
-            y: glyph.transform[5],
+        let [xx, xy, yx, yy, x, y] = glyph.transform;
+        let transform = Transform::new(xx, xy, yx, yy, x, y);
+        // Color glyphs are only requested for the color atlas, so the alpha
+        // atlas is limited to monochrome outlines.
+        let (sources, mask_format): (&[Source], _) = match self.format {
+            Format::A8 => (&[Source::Outline], zeno::Format::Alpha),
+            Format::Rgba8 => (
+                &[
+                    Source::ColorOutline(0),
+                    Source::ColorBitmap(StrikeWith::BestFit),
+                ],
+                zeno::Format::Alpha,
+            ),
+            Format::SubpixelRgb => (&[Source::Outline], zeno::Format::Subpixel),
+            Format::SubpixelBgr => (&[Source::Outline], zeno::Format::subpixel_bgra()),
         };
         self.image.clear();
-        Render::new(&[Source::ColorOutline(0), Source::Outline])
+        Render::new(sources)
+            .format(mask_format)
             .offset(Vector::new(glyph.subpx, 0.))
-            // See above
-            // .transform(Some(transform))
+            .embolden(glyph.embolden)
+            .transform(Some(transform))
             .render_into(&mut scaler, glyph.glyph_id, &mut self.image);
         let placement = self.image.placement;
-        let channels = match self.format {
-            Format::A8 => 1,
-            Format::Rgba8 => 4,
+        let channels = self.format.pixel_size();
+        let image_channels = match self.image.content {
+            Content::Color | Content::SubpixelMask => 4,
+            Content::Mask => 1,
         };
-        if placement.width == 0 || placement.height == 0 {
+        clear_rect(&glyph.rect, self.width, &mut self.pixbuf, channels);
+        self.rects.push(glyph.rect);
+        if placement.width == 0 || placement.height == 0 || image_channels != channels {
             return;
         }
-        copy_rect(
-            glyph.rect[0],
-            glyph.rect[1],
-            placement.width as usize,
-            &self.image.data,
+        // The placement of outlines includes the transform, but swash ignores
+        // it for bitmaps, so apply the translation here.
+        let (left, top) = match self.image.source {
+            Source::Bitmap(_) | Source::ColorBitmap(_) => (
+                placement.left + x.round() as i32,
+                placement.top + y.round() as i32,
+            ),
+            _ => (placement.left, placement.top),
+        };
+        // Convert the top left corner of the image from the y up space of
+        // the transform to rows of the rectangle.
+        copy_image(
+            &glyph.rect,
+            left,
+            glyph.rect[3] as i32 - top,
+            &self.image,
             self.width,
             &mut self.pixbuf,
             channels,
         );
     }
 
+    /// Writes the glyphs added since `begin` into a texture in system memory.
+    /// This is the CPU counterpart of `GlyphRasterizer::record`.
+    pub fn write_to(&self, target: &mut CpuTexture) {
+        let pixel_size = self.format.pixel_size();
+        for rect in &self.rects {
+            let offset = rect[1] as usize * self.stride + rect[0] as usize * pixel_size;
+            if let Some(data) = self.pixbuf.get(offset..) {
+                target.replace_region(*rect, data, self.stride);
+            }
+        }
+    }
+
+    /// Returns a copy of the pixel buffer as a texture. Only the rectangles
+    /// of glyphs added since `begin` hold meaningful contents.
+    pub fn to_texture(&self) -> CpuTexture {
+        CpuTexture {
+            format: self.format,
+            width: self.width as u32,
+            height: self.height as u32,
+            data: self.pixbuf[..self.stride * self.height].to_vec(),
+        }
+    }
+}
+
+impl Default for SoftwareGlyphRasterizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "metal")]
+impl GlyphRasterizer for SoftwareGlyphRasterizer {
+    fn new(_device: &DeviceRef, _queue: &CommandQueueRef) -> Self {
+        SoftwareGlyphRasterizer::new()
+    }
+
+    fn begin(&mut self, format: Format, width: u32, height: u32) {
+        SoftwareGlyphRasterizer::begin(self, format, width, height)
+    }
+
+    unsafe fn add_glyph(&mut self, glyph: &Glyph) {
+        SoftwareGlyphRasterizer::add_glyph(self, glyph)
+    }
+
     fn record(&mut self, _cmdbuf: &CommandBufferRef, target: &TextureRef) -> u32 {
-        target.replace_region(
-            MTLRegion::new_2d(0, 0, self.width as _, self.height as _),
-            0,
-            self.pixbuf.as_ptr() as _,
-            self.stride as _,
-        );
+        let pixel_size = self.format.pixel_size();
+        for rect in &self.rects {
+            let [x, y, width, height] = *rect;
+            let offset = y as usize * self.stride + x as usize * pixel_size;
+            let data = match self.pixbuf.get(offset..) {
+                Some(data) => data,
+                None => continue,
+            };
+            target.replace_region(
+                MTLRegion::new_2d(x as _, y as _, width as _, height as _),
+                0,
+                data.as_ptr() as _,
+                self.stride as _,
+            );
+        }
         !0
     }
 
     fn release(&mut self, _id: u32) {}
 }
 
-fn copy_rect(
-    x: u16,
-    y: u16,
-    width: usize,
-    image: &[u8],
-    target_width: usize,
-    target: &mut [u8],
-    channels: usize,
-) -> Option<()> {
-    let image_pitch = width * channels;
+fn clear_rect(rect: &[u16; 4], target_width: usize, target: &mut [u8], channels: usize) {
+    let pitch = rect[2] as usize * channels;
     let buffer_pitch = target_width * channels;
-    let mut offset = y as usize * buffer_pitch + x as usize * channels;
-    for row in image.chunks(image_pitch) {
-        let dest = target.get_mut(offset..offset + image_pitch)?;
-        dest.copy_from_slice(row);
+    let mut offset = rect[1] as usize * buffer_pitch + rect[0] as usize * channels;
+    for _ in 0..rect[3] {
+        if let Some(row) = target.get_mut(offset..offset + pitch) {
+            row.fill(0);
+        }
         offset += buffer_pitch;
     }
-    Some(())
 }
 
-mod tga {
-    use std::fs::File;
-    use std::io::{self, Write};
-    use std::slice;
-
-    fn write<W: Write, T>(w: &mut W, v: T) -> io::Result<()> {
-        let size = std::mem::size_of::<T>();
-        let bytes = unsafe { slice::from_raw_parts(&v as *const T as *const u8, size) };
-        w.write_all(bytes)
+/// Copies an image into the target buffer at an offset from the origin of
+/// the rectangle, clipping it to the rectangle.
+fn copy_image(
+    rect: &[u16; 4],
+    x: i32,
+    y: i32,
+    image: &Image,
+    target_width: usize,
+    target: &mut [u8],
+    channels: usize,
+) {
+    let width = image.placement.width as i32;
+    let height = image.placement.height as i32;
+    let x0 = x.max(0);
+    let y0 = y.max(0);
+    let x1 = (x + width).min(rect[2] as i32);
+    let y1 = (y + height).min(rect[3] as i32);
+    if x0 >= x1 || y0 >= y1 {
+        return;
     }
-
-    pub fn write_image(
-        buf: &[u8],
-        width: u32,
-        height: u32,
-        channels: u32,
-        path: &str,
-    ) -> io::Result<()> {
-        let f = File::create(path)?;
-        let mut f = std::io::BufWriter::new(f);
-        let w = width as usize;
-        let h = height as usize;
-        let fp = &mut f;
-        write(fp, 0u16)?;
-        write(fp, 2u8)?;
-        write(fp, 0u32)?;
-        write(fp, 0u8)?;
-        write(fp, 0u32)?;
-        write(fp, w as u16)?;
-        write(fp, h as u16)?;
-        write(fp, 32u8)?;
-        write(fp, 0u8)?;
-        let channels = channels as usize;
-        for y in 0..h {
-            let line = h - y - 1;
-            let offset = line * w * channels;
-            let mut x = 0;
-            while x < w * channels {
-                let rgba = if channels == 1 {
-                    let v = buf[offset + x];
-                    [v, v, v, 255]
-                } else {
-                    [
-                        buf[offset + x + 2],
-                        buf[offset + x + 1],
-                        buf[offset + x],
-                        buf[offset + x + 3],
-                    ]
-                };
-                write(fp, rgba)?;
-                x += channels;
-            }
-        }
-        f.flush()?;
-        io::Result::Ok(())
+    let image_pitch = width as usize * channels;
+    let buffer_pitch = target_width * channels;
+    let copy_pitch = (x1 - x0) as usize * channels;
+    for row in y0..y1 {
+        let src = (row - y) as usize * image_pitch + (x0 - x) as usize * channels;
+        let dst = (rect[1] as usize + row as usize) * buffer_pitch
+            + (rect[0] as usize + x0 as usize) * channels;
+        let (src, dst) = match (
+            image.data.get(src..src + copy_pitch),
+            target.get_mut(dst..dst + copy_pitch),
+        ) {
+            (Some(src), Some(dst)) => (src, dst),
+            _ => return,
+        };
+        dst.copy_from_slice(src);
     }
 }
@@ -0,0 +1,160 @@
+//! Export of textures in system memory as image files. This is intended for
+//! inspecting atlas pages and rendered frames and for producing test
+//! artifacts. PNG images can also be read back for comparison.
+
+use super::glyph_cache::CpuTexture;
+use super::glyph_rasterizer::Format;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    Png,
+    Tga,
+}
+
+impl ImageFormat {
+    /// Returns the format matching the extension of the path.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        if ext.eq_ignore_ascii_case("png") {
+            Some(Self::Png)
+        } else if ext.eq_ignore_ascii_case("tga") {
+            Some(Self::Tga)
+        } else {
+            None
+        }
+    }
+}
+
+/// Writes the texture to a file, choosing the format from the extension of
+/// the path. Paths without a known extension are written as PNG.
+pub fn write_image(path: impl AsRef<Path>, texture: &CpuTexture) -> io::Result<()> {
+    let path = path.as_ref();
+    match ImageFormat::from_path(path).unwrap_or(ImageFormat::Png) {
+        ImageFormat::Png => write_png(path, texture),
+        ImageFormat::Tga => write_tga(path, texture),
+    }
+}
+
+/// Writes the texture to a PNG file. Alpha textures are written as
+/// grayscale images.
+pub fn write_png(path: impl AsRef<Path>, texture: &CpuTexture) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    encode_png(&mut w, texture)?;
+    w.flush()
+}
+
+/// Encodes the texture as a PNG image.
+pub fn encode_png<W: Write>(w: W, texture: &CpuTexture) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(w, texture.width, texture.height);
+    encoder.set_color(match texture.format {
+        Format::A8 => png::ColorType::Grayscale,
+        _ => png::ColorType::RGBA,
+    });
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&texture.data)?;
+    Ok(())
+}
+
+/// Reads a PNG file into a texture. Grayscale images are read as alpha
+/// textures and all other images are converted to RGBA.
+pub fn read_png(path: impl AsRef<Path>) -> io::Result<CpuTexture> {
+    decode_png(BufReader::new(File::open(path)?))
+}
+
+/// Decodes a PNG image into a texture. See `read_png`.
+pub fn decode_png<R: Read>(r: R) -> io::Result<CpuTexture> {
+    let mut decoder = png::Decoder::new(r);
+    // Expands palettes and low bit depths to 8 bits per channel.
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut buf = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buf)?;
+    let (width, height) = (info.width, info.height);
+    if info.color_type == png::ColorType::Grayscale {
+        let mut texture = CpuTexture::new(Format::A8, width, height);
+        texture.replace_region([0, 0, width as u16, height as u16], &buf, info.line_size);
+        return Ok(texture);
+    }
+    let mut texture = CpuTexture::new(Format::Rgba8, width, height);
+    let channels = info.color_type.samples();
+    let width = width as usize;
+    for (src, dst) in buf
+        .chunks(info.line_size)
+        .zip(texture.data.chunks_mut(width * 4))
+    {
+        for (src, dst) in src.chunks(channels).zip(dst.chunks_mut(4)) {
+            let rgba = match *src {
+                [v] => [v, v, v, 255],
+                [v, a] => [v, v, v, a],
+                [r, g, b] => [r, g, b, 255],
+                [r, g, b, a] => [r, g, b, a],
+                _ => [0; 4],
+            };
+            dst.copy_from_slice(&rgba);
+        }
+    }
+    Ok(texture)
+}
+
+/// Writes the texture to an uncompressed 32-bit TGA file.
+pub fn write_tga(path: impl AsRef<Path>, texture: &CpuTexture) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    encode_tga(&mut w, texture)?;
+    w.flush()
+}
+
+/// Encodes the texture as an uncompressed 32-bit TGA image. Alpha textures
+/// are expanded to opaque gray.
+pub fn encode_tga<W: Write>(mut w: W, texture: &CpuTexture) -> io::Result<()> {
+    let width = texture.width as u16;
+    let height = texture.height as u16;
+    // No id or color map, uncompressed true color.
+    w.write_all(&[0, 0, 2])?;
+    w.write_all(&[0; 5])?;
+    // Origin, size, bits per pixel and descriptor. The descriptor holds the
+    // number of alpha bits with the origin at the bottom left.
+    w.write_all(&[0; 4])?;
+    w.write_all(&width.to_le_bytes())?;
+    w.write_all(&height.to_le_bytes())?;
+    w.write_all(&[32, 8])?;
+    let stride = texture.stride();
+    let mut line = Vec::with_capacity(width as usize * 4);
+    // Rows are stored bottom to top.
+    for row in texture.data.chunks(stride.max(1)).rev() {
+        line.clear();
+        match texture.format {
+            Format::A8 => {
+                for &v in row {
+                    line.extend_from_slice(&[v, v, v, 255]);
+                }
+            }
+            _ => {
+                for rgba in row.chunks(4) {
+                    line.extend_from_slice(&[rgba[2], rgba[1], rgba[0], rgba[3]]);
+                }
+            }
+        }
+        w.write_all(&line)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tga_has_alpha_channel() {
+        let mut texture = CpuTexture::new(Format::Rgba8, 1, 2);
+        texture.data.copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut tga = vec![];
+        encode_tga(&mut tga, &texture).unwrap();
+        assert_eq!(tga[16..18], [32, 8]);
+        // Bottom row first, in BGRA order.
+        assert_eq!(tga[18..], [7, 6, 5, 8, 3, 2, 1, 4]);
+    }
+}
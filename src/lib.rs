@@ -1,7 +1,12 @@
+pub mod cpu_render;
 pub mod font;
+mod frame;
 pub mod glyph;
 pub mod glyph_cache;
 pub mod glyph_rasterizer;
+pub mod image;
+#[cfg(feature = "metal")]
 pub mod pgpu_rasterizer;
+#[cfg(feature = "metal")]
 pub mod render;
 pub mod text;
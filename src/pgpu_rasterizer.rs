@@ -1,9 +1,11 @@
 use piet_gpu::{glyph_render::GlyphRenderer, PixelFormat, RenderConfig};
 use piet_gpu_hal::{QueryPool, Session};
+use std::collections::HashMap;
 
-use super::glyph_rasterizer::GlyphRasterizer;
+use super::glyph_rasterizer::{GlyphRasterizer, SoftwareGlyphRasterizer};
 
 pub struct PgpuRasterizer {
+    device: metal::Device,
     session: Session,
     glyph_renderer: GlyphRenderer,
     pgpu_renderer: Option<piet_gpu::Renderer>,
@@ -11,6 +13,41 @@ pub struct PgpuRasterizer {
     width: u32,
     height: u32,
     format: crate::glyph_rasterizer::Format,
+    // The piet-gpu glyph renderer always loads the first face of the font
+    // data, so faces from collections are copied out into standalone fonts.
+    faces: HashMap<u64, Vec<u8>>,
+    // piet-gpu renders the full image, so glyphs are rendered into a staging
+    // texture and only the rectangles of new glyphs are copied to the atlas.
+    staging: Option<metal::Texture>,
+    rects: Vec<[u16; 4]>,
+    // Glyphs that piet-gpu can't render correctly are rasterized with swash
+    // instead. See `needs_fallback`.
+    fallback: SoftwareGlyphRasterizer,
+    // Command buffer of the last batch rendered by piet-gpu. The renderer
+    // has a single scene buffer, so it must complete before the scene of the
+    // next batch is uploaded.
+    in_flight: Option<metal::CommandBuffer>,
+}
+
+impl PgpuRasterizer {
+    /// Returns true if the glyph must be rasterized in software. The glyph
+    /// renderer only draws unhinted monochrome outlines of the default
+    /// instance of a font with a single coverage value, so variations,
+    /// hinting, synthetic emboldening, color glyphs and subpixel coverage are
+    /// all left to swash. Oblique glyphs are handled by the transform.
+    fn needs_fallback(&self, glyph: &crate::glyph::Glyph) -> bool {
+        self.format != crate::glyph_rasterizer::Format::A8
+            || glyph.num_variations != 0
+            || glyph.hint
+            || glyph.embolden != 0.
+    }
+
+    /// Waits for the GPU to finish the last batch rendered by piet-gpu.
+    fn wait_for_last_batch(&mut self) {
+        if let Some(cmdbuf) = self.in_flight.take() {
+            cmdbuf.wait_until_completed();
+        }
+    }
 }
 
 impl GlyphRasterizer for PgpuRasterizer {
@@ -20,6 +57,7 @@ impl GlyphRasterizer for PgpuRasterizer {
         let glyph_renderer = GlyphRenderer::new();
         let query_pool = session.create_query_pool(8).unwrap();
         PgpuRasterizer {
+            device: device.to_owned(),
             session,
             glyph_renderer,
             pgpu_renderer: None,
@@ -27,60 +65,127 @@ impl GlyphRasterizer for PgpuRasterizer {
             width: 0,
             height: 0,
             format: crate::glyph_rasterizer::Format::A8,
+            faces: HashMap::new(),
+            staging: None,
+            rects: vec![],
+            fallback: SoftwareGlyphRasterizer::new(),
+            in_flight: None,
         }
     }
 
     fn begin(&mut self, format: crate::glyph_rasterizer::Format, width: u32, height: u32) {
-        if self.pgpu_renderer.is_none()
-            || self.width != width
-            || self.height != height
-            || self.format != format
+        self.format = format;
+        // Only alpha masks are rendered by piet-gpu, so the renderer and
+        // staging texture are alpha only and kept across batches of other
+        // formats.
+        if format == crate::glyph_rasterizer::Format::A8
+            && (self.pgpu_renderer.is_none() || self.width != width || self.height != height)
         {
+            self.wait_for_last_batch();
             self.width = width;
             self.height = height;
-            self.format = format;
-            let format = match format {
-                crate::glyph_rasterizer::Format::A8 => PixelFormat::A8,
-                crate::glyph_rasterizer::Format::Rgba8 => PixelFormat::Rgba8,
-            };
-            let config = RenderConfig::new(width as usize, height as usize).pixel_format(format);
+            let config =
+                RenderConfig::new(width as usize, height as usize).pixel_format(PixelFormat::A8);
             unsafe {
                 self.pgpu_renderer =
                     piet_gpu::Renderer::new_from_config(&self.session, config, 1).ok();
             }
+            let desc = metal::TextureDescriptor::new();
+            desc.set_width(width as _);
+            desc.set_height(height as _);
+            desc.set_pixel_format(metal::MTLPixelFormat::A8Unorm);
+            desc.set_usage(
+                metal::MTLTextureUsage::ShaderRead | metal::MTLTextureUsage::ShaderWrite,
+            );
+            self.staging = Some(self.device.new_texture(&desc));
         }
+        self.rects.clear();
+        self.fallback.begin(format, width, height);
     }
 
     unsafe fn add_glyph(&mut self, glyph: &crate::glyph::Glyph) {
-        let font_data = std::slice::from_raw_parts(glyph.font_data, glyph.font_data_len as usize);
-        // TODO: fine-tune transform (including subpix)
+        if self.needs_fallback(glyph) {
+            self.fallback.add_glyph(glyph);
+            return;
+        }
+        let mut font_data =
+            std::slice::from_raw_parts(glyph.font_data, glyph.font_data_len as usize);
+        if glyph.font_offset != 0 {
+            let face = self.faces.entry(glyph.unique_id).or_insert_with(|| {
+                crate::font::extract_face(font_data, glyph.font_offset).expect("invalid font")
+            });
+            font_data = face;
+        }
+        // TODO: piet-gpu's glyph renderer loads outlines itself and has no
+        // way to accept variation coordinates, hinting or emboldening.
+        // Building the outlines with swash would let those glyphs stay on
+        // the GPU instead of falling back.
+        // Outlines are in ems and piet-gpu targets the atlas with y pointing
+        // down, so scale by the font size, apply the glyph transform and then
+        // flip into the rectangle. See `Glyph::transform`.
+        let [xx, xy, yx, yy, x, y] = glyph.transform;
+        let size = glyph.font_size;
+        let [rect_x, rect_y, _, rect_height] = glyph.rect;
         let transform = [
-            glyph.font_size,
-            0.0,
-            0.0,
-            -glyph.font_size,
-            glyph.rect[0] as f32,
-            glyph.rect[3] as f32,
+            size * xx,
+            -size * xy,
+            size * yx,
+            -size * yy,
+            rect_x as f32 + x + glyph.subpx,
+            (rect_y + rect_height) as f32 - y,
         ];
         self.glyph_renderer
             .add_glyph(font_data, glyph.unique_id, glyph.glyph_id, transform);
+        self.rects.push(glyph.rect);
     }
 
     fn record(&mut self, cmdbuf: &metal::CommandBufferRef, target: &metal::TextureRef) -> u32 {
+        // Software glyphs are written directly and don't overlap the
+        // rectangles of the glyphs rendered by piet-gpu.
+        self.fallback.record(cmdbuf, target);
+        if self.rects.is_empty() {
+            return 0;
+        }
+        // The caller commits each batch before starting the next one, so
+        // this doesn't wait on a command buffer that was never committed.
+        self.wait_for_last_batch();
+        let staging = match &self.staging {
+            Some(staging) => staging,
+            None => return 0,
+        };
         unsafe {
             let mut cmd_buf = self.session.cmd_buf_from_raw_mtl(cmdbuf);
-            let dst_image = self
+            let staging_image = self
                 .session
-                .image_from_raw_mtl(target, self.width, self.height);
+                .image_from_raw_mtl(staging, self.width, self.height);
             if let Some(renderer) = &mut self.pgpu_renderer {
                 renderer
                     .upload_render_ctx(&mut self.glyph_renderer.render_ctx, 0)
                     .unwrap();
                 renderer.record(&mut cmd_buf, &self.query_pool, 0);
-                // TODO later: we can bind the destination image and avoid the copy.
-                cmd_buf.blit_image(&renderer.image_dev, &dst_image);
+                // TODO later: we can bind the staging image and avoid the copy.
+                cmd_buf.blit_image(&renderer.image_dev, &staging_image);
+            }
+        }
+        let encoder = cmdbuf.new_blit_command_encoder();
+        for rect in &self.rects {
+            let [x, y, width, height] = *rect;
+            let origin = metal::MTLOrigin {
+                x: x as _,
+                y: y as _,
+                z: 0,
+            };
+            let size = metal::MTLSize {
+                width: width as _,
+                height: height as _,
+                depth: 1,
+            };
+            if width != 0 && height != 0 {
+                encoder.copy_from_texture(staging, 0, 0, origin, size, target, 0, 0, origin);
             }
         }
+        encoder.end_encoding();
+        self.in_flight = Some(cmdbuf.to_owned());
         // Return a token which will be used by release. Probably the right thing to do is use
         // it to select a buffer index from the pool.
         0
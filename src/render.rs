@@ -1,11 +1,8 @@
-use super::font::Font;
-use super::glyph_cache::{GlyphCache, GlyphKey, SubpixelOffset};
+use super::frame::{FrameQuad, GlyphContext};
+use super::glyph_cache::{Antialias, CpuTexture, MetalAtlasStorage, ATLAS_SIZE};
 use super::glyph_rasterizer::{Format, GlyphRasterizer};
 use super::text::Text;
 use metal::*;
-use std::ops::Range;
-use swash::scale::{outline::Outline, ScaleContext};
-use swash::zeno::{Origin, Placement};
 
 const TARGET_FORMAT: MTLPixelFormat = MTLPixelFormat::BGRA8Unorm;
 
@@ -15,14 +12,13 @@ pub struct Renderer<G> {
     queue: CommandQueue,
     width: u32,
     height: u32,
-    glyph_cache: GlyphCache,
+    glyphs: GlyphContext<MetalAtlasStorage>,
     glyph_rasterizer: G,
-    scale_ctx: ScaleContext,
-    glyphs: Vec<RenderGlyph>,
-    runs: Vec<RenderRun>,
     quads: QuadBatch,
     alpha_pso: RenderPipelineState,
     color_pso: RenderPipelineState,
+    subpixel_pso: RenderPipelineState,
+    solid_pso: RenderPipelineState,
 }
 
 impl<G: GlyphRasterizer> Renderer<G> {
@@ -34,29 +30,55 @@ impl<G: GlyphRasterizer> Renderer<G> {
         layer.set_presents_with_transaction(false);
         let queue = device.new_command_queue();
         let glyph_rasterizer = G::new(&device, &queue);
-        let glyph_cache = GlyphCache::new(device.clone());
+        let glyphs = GlyphContext::new(MetalAtlasStorage::new(device.clone()));
         let quads = QuadBatch::new(&device);
         let options = CompileOptions::new();
         options.set_language_version(MTLLanguageVersion::V2_2);
         let library = device
             .new_library_with_source(SHADER_SOURCE, &options)
             .unwrap();
-        let alpha_pso = build_pso(&device, &library, "alpha_frag");
-        let color_pso = build_pso(&device, &library, "color_frag");
+        let alpha_pso = build_pso(
+            &device,
+            &library,
+            "alpha_frag",
+            MTLBlendFactor::SourceAlpha,
+            MTLBlendFactor::OneMinusSourceAlpha,
+        );
+        let color_pso = build_pso(
+            &device,
+            &library,
+            "color_frag",
+            MTLBlendFactor::SourceAlpha,
+            MTLBlendFactor::OneMinusSourceAlpha,
+        );
+        // Subpixel coverage is blended per channel with dual source blending.
+        let subpixel_pso = build_pso(
+            &device,
+            &library,
+            "subpixel_frag",
+            MTLBlendFactor::One,
+            MTLBlendFactor::OneMinusSource1Color,
+        );
+        let solid_pso = build_pso(
+            &device,
+            &library,
+            "solid_frag",
+            MTLBlendFactor::SourceAlpha,
+            MTLBlendFactor::OneMinusSourceAlpha,
+        );
         Self {
             device,
             layer,
             queue,
             width: 0,
             height: 0,
-            glyph_cache,
+            glyphs,
             glyph_rasterizer,
-            scale_ctx: ScaleContext::new(),
-            glyphs: vec![],
-            runs: vec![],
             quads,
             alpha_pso,
             color_pso,
+            subpixel_pso,
+            solid_pso,
         }
     }
 
@@ -67,103 +89,85 @@ impl<G: GlyphRasterizer> Renderer<G> {
         self.height = height;
     }
 
-    pub fn new_frame(&mut self, bg_color: [f32; 4]) -> FrameRenderer<G> {
-        self.glyphs.clear();
-        self.runs.clear();
-        FrameRenderer {
-            r: self,
-            bg_color,
-            flush_cache: false,
-        }
+    /// Sets the number of bytes of atlas space that cached glyphs may occupy
+    /// before least recently used glyphs are evicted.
+    pub fn set_glyph_cache_budget(&mut self, budget: usize) {
+        self.glyphs.set_budget(budget);
+    }
+
+    /// Enables hinting of glyph outlines. Hinting snaps outlines to the pixel
+    /// grid, which sharpens small text on low density displays.
+    pub fn set_hinting(&mut self, hint: bool) {
+        self.glyphs.set_hinting(hint);
+    }
+
+    /// Sets the antialiasing of glyphs rendered from monochrome outlines.
+    /// Subpixel antialiasing assumes the text is drawn over opaque content
+    /// on a display with matching subpixels.
+    pub fn set_antialias(&mut self, antialias: Antialias) {
+        self.glyphs.set_antialias(antialias);
+    }
+
+    /// Reads back the contents of a page in the color or alpha atlas. This
+    /// waits for the GPU and is intended for debugging.
+    pub fn read_atlas_page(&self, is_color: bool, page: usize) -> Option<CpuTexture> {
+        let (format, pages) = if is_color {
+            (Format::Rgba8, &self.glyphs.cache.color)
+        } else {
+            (Format::A8, &self.glyphs.cache.alpha)
+        };
+        let texture = &pages.get(page)?.texture;
+        let width = texture.width() as u32;
+        let height = texture.height() as u32;
+        // Managed textures must be synchronized before the CPU copy is
+        // valid.
+        let cmdbuf = self.queue.new_command_buffer();
+        let encoder = cmdbuf.new_blit_command_encoder();
+        encoder.synchronize_resource(texture);
+        encoder.end_encoding();
+        cmdbuf.commit();
+        cmdbuf.wait_until_completed();
+        let mut image = CpuTexture::new(format, width, height);
+        let stride = image.stride();
+        texture.get_bytes(
+            image.data.as_mut_ptr() as _,
+            stride as _,
+            MTLRegion::new_2d(0, 0, width as _, height as _),
+            0,
+        );
+        Some(image)
+    }
+
+    pub fn new_frame(&mut self, bg_color: [f32; 4]) -> FrameRenderer<'_, G> {
+        self.glyphs.begin_frame();
+        FrameRenderer { r: self, bg_color }
     }
 }
 
 pub struct FrameRenderer<'a, G> {
     r: &'a mut Renderer<G>,
     bg_color: [f32; 4],
-    flush_cache: bool,
 }
 
 impl<'a, G: GlyphRasterizer> FrameRenderer<'a, G> {
     pub fn draw_text(&mut self, x: f32, y: f32, text: &Text) {
-        for line in &text.lines {
-            let baseline = y + line.y + line.ascent;
-            let mut pen_x = x;
-            for run in &line.runs {
-                let is_color = self
-                    .r
-                    .scale_ctx
-                    .builder(run.font.as_ref())
-                    .build()
-                    .has_color_outlines();
-                let start = self.r.glyphs.len();
-                for (id, advance) in run.ids.iter().zip(&run.advances) {
-                    let subpx = SubpixelOffset::quantize(pen_x);
-                    self.r.glyphs.push(RenderGlyph {
-                        id: *id,
-                        x: (pen_x + 0.125).floor(),
-                        y: baseline,
-                        subpx,
-                    });
-                    if !self.flush_cache
-                        && self
-                            .r
-                            .glyph_cache
-                            .map
-                            .get(&GlyphKey {
-                                font_id: run.font.key,
-                                font_size: run.font_size.to_bits(),
-                                subpx,
-                                id: *id,
-                            })
-                            .is_none()
-                    {
-                        self.flush_cache = true;
-                    }
-                    pen_x += *advance;
-                }
-                let end = self.r.glyphs.len();
-                let [r, g, b, a] = run.color;
-                let color = [
-                    (r * 255.) as u8,
-                    (g * 255.) as u8,
-                    (b * 255.) as u8,
-                    (a * 255.) as u8,
-                ];
-                self.r.runs.push(RenderRun {
-                    font: run.font.clone(),
-                    font_size: run.font_size,
-                    is_color,
-                    color,
-                    glyphs: start..end,
-                });
-            }
-        }
+        self.r.glyphs.add_text(x, y, text);
     }
 
     pub fn render(mut self) {
-        if self.flush_cache {
-            self.build_cache();
-        }
-        self.r.quads.prepare(self.r.glyphs.len());
-        for run in &self.r.runs {
-            let glyphs = self.r.glyphs.get(run.glyphs.clone()).unwrap();
-            for glyph in glyphs {
-                if let Some(entry) = self.r.glyph_cache.map.get(&GlyphKey {
-                    font_id: run.font.key,
-                    font_size: run.font_size.to_bits(),
-                    subpx: glyph.subpx,
-                    id: glyph.id,
-                }) {
-                    let x0 = glyph.x + entry.left as f32;
-                    let y0 = glyph.y - entry.top as f32;
-                    let x1 = x0 + entry.width as f32;
-                    let y1 = y0 + entry.height as f32;
-                    if entry.width != 0 && entry.height != 0 {
-                        self.r
-                            .quads
-                            .add_rect(&[x0, y0, x1, y1], &entry.uv, run.color);
-                    }
+        self.update_cache();
+        self.r.quads.prepare(self.r.glyphs.frame.len());
+        for quad in self.r.glyphs.quads() {
+            match quad {
+                FrameQuad::Glyph(quad) => self.r.quads.add_rect(
+                    &quad.rect,
+                    &quad.uv,
+                    quad.color,
+                    Some(quad.format),
+                    quad.page,
+                ),
+                FrameQuad::Solid { rect, color } => {
+                    self.r.quads.add_rect(&rect, &[0.; 4], color, None, 0)
                 }
             }
         }
@@ -181,18 +185,33 @@ impl<'a, G: GlyphRasterizer> FrameRenderer<'a, G> {
         color_attachment.set_store_action(MTLStoreAction::Store);
         let cmdbuf = self.r.queue.new_command_buffer();
         let encoder = cmdbuf.new_render_command_encoder(&pass);
-        if let Some(alpha_atlas) = self.r.glyph_cache.alpha.as_ref() {
-            let vp_size = [self.r.width, self.r.height];
-            encoder.set_render_pipeline_state(&self.r.alpha_pso);
-            encoder.set_vertex_buffer(0, Some(&self.r.quads.vertex_buffer), 0);
-            encoder.set_vertex_bytes(1, 8, vp_size.as_ptr() as _);
-            encoder.set_fragment_texture(0, Some(&alpha_atlas.texture));
+        let vp_size = [self.r.width, self.r.height];
+        encoder.set_vertex_buffer(0, Some(&self.r.quads.vertex_buffer), 0);
+        encoder.set_vertex_bytes(1, 8, vp_size.as_ptr() as _);
+        // Ranges switch between glyph formats, atlas pages and solid quads
+        // in submission order so overlapping quads composite correctly.
+        for range in &self.r.quads.ranges {
+            let pso = match range.format {
+                Some(Format::A8) => &self.r.alpha_pso,
+                Some(Format::Rgba8) => &self.r.color_pso,
+                Some(Format::SubpixelRgb | Format::SubpixelBgr) => &self.r.subpixel_pso,
+                None => &self.r.solid_pso,
+            };
+            let texture = match range.format {
+                Some(format) => match self.r.glyphs.atlas(format, range.page) {
+                    Some(atlas) => Some(&*atlas.texture),
+                    None => continue,
+                },
+                None => None,
+            };
+            encoder.set_render_pipeline_state(pso);
+            encoder.set_fragment_texture(0, texture);
             encoder.draw_indexed_primitives(
                 MTLPrimitiveType::Triangle,
-                self.r.quads.indices.len() as _,
+                (range.end - range.start) as _,
                 MTLIndexType::UInt32,
                 &self.r.quads.index_buffer,
-                0,
+                (range.start * 4) as _,
             );
         }
         encoder.end_encoding();
@@ -200,98 +219,29 @@ impl<'a, G: GlyphRasterizer> FrameRenderer<'a, G> {
         cmdbuf.commit();
     }
 
-    fn build_cache(&mut self) {
-        println!("rebuilding cache!");
-        use super::glyph::Glyph;
-        use super::glyph_cache::ATLAS_SIZE;
-        self.r.glyph_cache.clear();
-        let mut outline = Outline::new();
-        let alpha = self.r.runs.iter().filter(|r| !r.is_color);
-        let _color = self.r.runs.iter().filter(|r| r.is_color);
-        self.r
-            .glyph_rasterizer
-            .begin(Format::A8, ATLAS_SIZE, ATLAS_SIZE);
-        for run in alpha {
-            let mut scaler = self
-                .r
-                .scale_ctx
-                .builder(run.font.as_ref())
-                .size(run.font_size)
-                .build();
-            let glyphs = self.r.glyphs.get(run.glyphs.clone()).unwrap();
+    /// Rasterizes the glyphs of this frame that are missing from the cache.
+    /// Existing entries and their texels are left untouched.
+    fn update_cache(&mut self) {
+        let r = &mut *self.r;
+        let (rasterizer, queue) = (&mut r.glyph_rasterizer, &r.queue);
+        r.glyphs.update_cache(|format, texture, glyphs| {
+            rasterizer.begin(format, ATLAS_SIZE, ATLAS_SIZE);
             for glyph in glyphs {
-                let key = GlyphKey {
-                    font_id: run.font.key,
-                    font_size: run.font_size.to_bits(),
-                    subpx: glyph.subpx,
-                    id: glyph.id,
-                };
-                if !scaler.scale_outline_into(glyph.id, &mut outline) {
-                    continue;
-                }
-                let bounds = outline.bounds();
-                let (offset, placement) = Placement::compute(Origin::BottomLeft, (0, 0), &bounds);
-                if let Some(entry) = self.r.glyph_cache.insert(
-                    key,
-                    false,
-                    placement.width as u16,
-                    placement.height as u16,
-                ) {
-                    entry.left = placement.left as i16;
-                    entry.top = placement.top as i16;
-                    let transform = [1.0, 0.0, 0.0, 1.0, offset.x, offset.y];
-                    let font = run.font.as_ref();
-                    let rect = [
-                        (entry.uv[0] * ATLAS_SIZE as f32) as u16,
-                        (entry.uv[1] * ATLAS_SIZE as f32) as u16,
-                        placement.width as u16,
-                        placement.height as u16,
-                    ];
-                    unsafe {
-                        self.r.glyph_rasterizer.add_glyph(&Glyph {
-                            unique_id: font.key.value(),
-                            font_data: font.data.as_ptr(),
-                            font_data_len: font.data.len() as _,
-                            font_size: run.font_size,
-                            glyph_id: glyph.id,
-                            transform,
-                            rect,
-                            subpx: glyph.subpx.to_f32(),
-                            variations: std::ptr::null(),
-                            num_variations: 0,
-                        });
-                    }
-                } else {
-                    continue;
+                // The font data is owned by the runs for this frame.
+                unsafe {
+                    rasterizer.add_glyph(glyph);
                 }
             }
-        }
-        if let Some(texture) = self.r.glyph_cache.alpha.as_ref().map(|a| &a.texture) {
-            let cmdbuf = self.r.queue.new_command_buffer();
-            let id = self.r.glyph_rasterizer.record(cmdbuf, texture);
+            let cmdbuf = queue.new_command_buffer();
+            let id = rasterizer.record(cmdbuf, texture);
             cmdbuf.commit();
             // Consider a fence here; not strictly necessary in Metal's default
             // configuration
-            self.r.glyph_rasterizer.release(id);
-        }
+            rasterizer.release(id);
+        });
     }
 }
 
-struct RenderRun {
-    font: Font,
-    font_size: f32,
-    is_color: bool,
-    color: [u8; 4],
-    glyphs: Range<usize>,
-}
-
-struct RenderGlyph {
-    id: u16,
-    x: f32,
-    y: f32,
-    subpx: SubpixelOffset,
-}
-
 #[derive(Copy, Clone)]
 #[repr(C)]
 struct Vertex {
@@ -300,6 +250,16 @@ struct Vertex {
     color: [u8; 4],
 }
 
+/// Range of indices drawn from a single atlas page with a single format.
+struct QuadRange {
+    start: u32,
+    end: u32,
+    /// Format of the atlas page, or `None` for solid quads that don't
+    /// sample an atlas.
+    format: Option<Format>,
+    page: u16,
+}
+
 const VERTEX_SIZE: usize = std::mem::size_of::<Vertex>();
 fn buffer_options() -> metal::MTLResourceOptions {
     metal::MTLResourceOptions::CPUCacheModeDefaultCache
@@ -313,7 +273,7 @@ struct QuadBatch {
     vertex_buffer: Buffer,
     index_buffer: Buffer,
     buffer_cap: usize,
-    ranges: Vec<(u32, u32, bool)>,
+    ranges: Vec<QuadRange>,
 }
 
 impl QuadBatch {
@@ -330,22 +290,29 @@ impl QuadBatch {
         }
     }
 
-    fn prepare(&mut self, num_glyphs: usize) {
-        if self.buffer_cap < num_glyphs {
+    fn prepare(&mut self, num_quads: usize) {
+        if self.buffer_cap < num_quads {
             self.vertex_buffer = self
                 .device
-                .new_buffer((num_glyphs * 4 * VERTEX_SIZE) as _, buffer_options());
+                .new_buffer((num_quads * 4 * VERTEX_SIZE) as _, buffer_options());
             self.index_buffer = self
                 .device
-                .new_buffer((num_glyphs * 6 * 4) as _, buffer_options());
-            self.buffer_cap = num_glyphs;
+                .new_buffer((num_quads * 6 * 4) as _, buffer_options());
+            self.buffer_cap = num_quads;
         }
         self.vertices.clear();
         self.indices.clear();
         self.ranges.clear();
     }
 
-    fn add_rect(&mut self, rect: &[f32; 4], uv: &[f32; 4], color: [u8; 4]) {
+    fn add_rect(
+        &mut self,
+        rect: &[f32; 4],
+        uv: &[f32; 4],
+        color: [u8; 4],
+        format: Option<Format>,
+        page: u16,
+    ) {
         let verts = [
             Vertex {
                 pos: [rect[0], rect[1]],
@@ -371,8 +338,19 @@ impl QuadBatch {
         let vertex_base = self.vertices.len() as u32;
         self.vertices.extend_from_slice(&verts);
         const QUAD_INDICES: [u32; 6] = [0, 1, 2, 2, 0, 3];
+        let index_base = self.indices.len() as u32;
         self.indices
             .extend(QUAD_INDICES.iter().map(|i| i + vertex_base));
+        let index_end = self.indices.len() as u32;
+        match self.ranges.last_mut() {
+            Some(range) if range.format == format && range.page == page => range.end = index_end,
+            _ => self.ranges.push(QuadRange {
+                start: index_base,
+                end: index_end,
+                format,
+                page,
+            }),
+        }
     }
 
     fn update_buffers(&mut self) {
@@ -397,7 +375,13 @@ impl QuadBatch {
     }
 }
 
-fn build_pso(device: &Device, library: &Library, frag_name: &str) -> RenderPipelineState {
+fn build_pso(
+    device: &Device,
+    library: &Library,
+    frag_name: &str,
+    src_factor: MTLBlendFactor,
+    dst_factor: MTLBlendFactor,
+) -> RenderPipelineState {
     let desc = RenderPipelineDescriptor::new();
     let vs = library.get_function("vert", None).unwrap();
     let fs = library.get_function(frag_name, None).unwrap();
@@ -407,8 +391,8 @@ fn build_pso(device: &Device, library: &Library, frag_name: &str) -> RenderPipel
     attachment.set_pixel_format(TARGET_FORMAT);
     attachment.set_blending_enabled(true);
     attachment.set_rgb_blend_operation(MTLBlendOperation::Add);
-    attachment.set_destination_rgb_blend_factor(MTLBlendFactor::OneMinusSourceAlpha);
-    attachment.set_source_rgb_blend_factor(MTLBlendFactor::SourceAlpha);
+    attachment.set_destination_rgb_blend_factor(dst_factor);
+    attachment.set_source_rgb_blend_factor(src_factor);
     let vdesc = VertexDescriptor::new();
     vdesc
         .layouts()
@@ -473,4 +457,27 @@ fragment float4 color_frag(
     constexpr sampler samp (mag_filter::nearest, min_filter::nearest);
     return texture.sample(samp, in.uv) * in.color;
   }
+
+struct SubpixelOut {
+    float4 color [[color(0), index(0)]];
+    float4 mask [[color(0), index(1)]];
+};
+
+// Per channel coverage is output as the second source so that each color
+// channel of the target is blended with its own coverage.
+fragment SubpixelOut subpixel_frag(
+    FragData in [[stage_in]],
+    texture2d<float> texture [[ texture(0) ]]
+   ) {
+    constexpr sampler samp (mag_filter::nearest, min_filter::nearest);
+    float3 coverage = texture.sample(samp, in.uv).rgb * in.color.a;
+    SubpixelOut out;
+    out.mask = float4(coverage, max(max(coverage.r, coverage.g), coverage.b));
+    out.color = float4(in.color.rgb * coverage, out.mask.a);
+    return out;
+  }
+
+fragment float4 solid_frag(FragData in [[stage_in]]) {
+    return in.color;
+  }
 "#;
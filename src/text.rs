@@ -1,99 +1,1772 @@
-use super::font::Font;
+use super::font::{Font, FontFallback};
+pub use super::glyph::Synthesis;
+use super::glyph::OBLIQUE_ANGLE;
+use std::ops::Range;
+use swash::scale::{outline::Outline, ScaleContext};
+use swash::shape::ShapeContext;
+use swash::text::cluster::{Boundary, CharCluster, CharInfo, Parser, Token};
+use swash::text::{analyze, Codepoint, Script};
+use swash::zeno::{Mask, Origin, Placement};
+use swash::Setting;
+use unicode_bidi::{BidiInfo, Level};
+
+/// Base direction of a paragraph.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Direction {
+    /// Determined by the first strong character in the paragraph.
+    Auto,
+    LeftToRight,
+    RightToLeft,
+}
+
+/// Horizontal alignment of the lines in a paragraph. Start and end are
+/// relative to the base direction of the paragraph.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Alignment {
+    Start,
+    End,
+    Center,
+    /// Spreads extra space across word gaps. The last line of a paragraph
+    /// is aligned to the start.
+    Justify,
+}
+
+/// Handling of text that exceeds the maximum number of lines or height of
+/// a `TextBuilder`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Overflow {
+    /// Lines past the limits are dropped.
+    Clip,
+    /// Lines past the limits are dropped and the last visible line ends
+    /// with an ellipsis.
+    EndEllipsis,
+    /// Paragraphs are not wrapped and the middle of lines wider than the
+    /// maximum width is replaced with an ellipsis. Suited to file paths.
+    MiddleEllipsis,
+    /// Paragraphs are not wrapped and the start of lines wider than the
+    /// maximum width is replaced with an ellipsis.
+    StartEllipsis,
+}
+
+/// Style of decoration lines.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub enum DecorationStyle {
+    #[default]
+    Solid,
+    Double,
+    Dotted,
+    Wavy,
+}
+
+/// Position of a decoration line relative to the text.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DecorationKind {
+    Underline,
+    Strikethrough,
+    Overline,
+}
+
+/// Lines drawn along a span of text.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct Decoration {
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub overline: bool,
+    pub style: DecorationStyle,
+    /// Color of the lines. The color of the span is used when this is
+    /// `None`.
+    pub color: Option<[f32; 4]>,
+    /// Interrupts underlines where they would cross glyph descenders.
+    pub skip_ink: bool,
+}
+
+impl Decoration {
+    fn is_empty(&self) -> bool {
+        !(self.underline || self.strikethrough || self.overline)
+    }
+}
+
+/// Style of a span of text.
+#[derive(Clone)]
+pub struct Style {
+    pub fonts: FontFallback,
+    pub font_size: f32,
+    pub color: [f32; 4],
+    /// OpenType feature settings, such as `("liga", 0)`.
+    pub features: Vec<Setting<u16>>,
+    pub synthesis: Synthesis,
+    pub decoration: Decoration,
+}
+
+impl Style {
+    pub fn new(fonts: impl Into<FontFallback>, font_size: f32, color: [f32; 4]) -> Self {
+        Self {
+            fonts: fonts.into(),
+            font_size,
+            color,
+            features: vec![],
+            synthesis: Synthesis::default(),
+            decoration: Decoration::default(),
+        }
+    }
+
+    /// Replaces the part of the style set by the attribute.
+    pub fn apply(&mut self, attribute: &Attribute) {
+        match attribute {
+            Attribute::Fonts(fonts) => self.fonts = fonts.clone(),
+            Attribute::FontSize(font_size) => self.font_size = *font_size,
+            Attribute::Color(color) => self.color = *color,
+            Attribute::Features(features) => self.features = features.clone(),
+            Attribute::Synthesis(synthesis) => self.synthesis = *synthesis,
+            Attribute::Decoration(decoration) => self.decoration = *decoration,
+        }
+    }
+
+    /// Returns true if text in both styles is shaped identically, so the
+    /// styles differ at most in colors and decorations.
+    fn is_shaped_like(&self, other: &Style) -> bool {
+        let fonts = self.fonts.fonts();
+        let other_fonts = other.fonts.fonts();
+        self.font_size == other.font_size
+            && self.features == other.features
+            && self.synthesis == other.synthesis
+            && fonts.len() == other_fonts.len()
+            && fonts.iter().zip(other_fonts).all(|(a, b)| {
+                a.key == b.key && a.offset == b.offset && a.variations_key == b.variations_key
+            })
+    }
+}
+
+/// Attribute that overrides part of the style for a range of an attributed
+/// string.
+#[derive(Clone)]
+pub enum Attribute {
+    Fonts(FontFallback),
+    FontSize(f32),
+    Color([f32; 4]),
+    Features(Vec<Setting<u16>>),
+    Synthesis(Synthesis),
+    Decoration(Decoration),
+}
 
 pub struct TextBuilder {
+    shape_ctx: ShapeContext,
+    // Only used to find the ink of glyphs for decorations that skip it.
+    scale_ctx: ScaleContext,
     max_width: f32,
-    x: f32,
+    max_lines: usize,
+    max_height: f32,
+    overflow: Overflow,
     y: f32,
+    // Source offset of the current paragraph.
+    offset: usize,
     lines: Vec<Line>,
+    line_alignments: Vec<LineAlignment>,
+    direction: Direction,
+    alignment: Alignment,
+    synthesis: Synthesis,
+    decoration: Decoration,
+    // Layout is deferred until the text is complete so that bidi
+    // resolution can see whole paragraphs and so that the text can either
+    // be built or measured.
+    paragraphs: Vec<Paragraph>,
+    // Text and spans of the current paragraph.
+    text: String,
+    spans: Vec<Span>,
 }
 
 pub struct Text {
     pub lines: Vec<Line>,
+    /// Whether lines were dropped or shortened to fit the limits of the
+    /// builder.
+    pub truncated: bool,
+    /// Byte range of the source text from the start of the first visible
+    /// line to the end of the last one. Text hidden by a middle ellipsis is
+    /// included; see `Line::elided`.
+    pub visible_range: Range<usize>,
+}
+
+/// Sizes of text measured by `TextBuilder::measure`. Only the advances of
+/// clusters are kept, so sizes can be found for any width without shaping
+/// again.
+pub struct Measurement {
+    paragraphs: Vec<MeasuredParagraph>,
+    /// Whether lines are wrapped, which depends on the overflow mode.
+    wraps: bool,
+    max_lines: usize,
+    max_height: f32,
+}
+
+/// Glyph identifier and positioning produced by the shaper.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ShapedGlyph {
+    pub id: u16,
+    /// Horizontal offset from the pen position.
+    pub x: f32,
+    /// Vertical offset from the baseline (positive is up).
+    pub y: f32,
+    pub advance: f32,
+}
+
+/// Mapping between a cluster of glyphs in a run and the source text.
+#[derive(Clone, Debug)]
+pub struct RunCluster {
+    /// Byte range of the cluster in the source text.
+    pub source: Range<usize>,
+    /// Range of the cluster's glyphs in the run.
+    pub glyphs: Range<usize>,
+    pub advance: f32,
 }
 
+/// Sequence of glyphs with a single style and direction. Glyphs and
+/// clusters are always stored in visual (left to right) order.
 pub struct Run {
     pub font: Font,
     pub font_size: f32,
     pub color: [f32; 4],
-    pub ids: Vec<u16>,
-    pub advances: Vec<f32>,
+    pub synthesis: Synthesis,
+    pub decoration: Decoration,
+    pub is_rtl: bool,
+    pub glyphs: Vec<ShapedGlyph>,
+    pub clusters: Vec<RunCluster>,
 }
 
+/// Line of text. Runs are stored in visual order.
 #[derive(Default)]
 pub struct Line {
+    /// Offset of the first run from the left edge of the text.
+    pub x: f32,
     pub y: f32,
+    /// Advance width of the line, excluding trailing whitespace.
+    pub width: f32,
+    /// Byte range of the line in the source text. For lines shortened by
+    /// a start or end ellipsis, this only covers the visible text.
+    pub range: Range<usize>,
+    /// Byte range of the source text replaced by an ellipsis. The cluster
+    /// of the ellipsis maps to this range.
+    pub elided: Option<Range<usize>>,
     pub ascent: f32,
     pub descent: f32,
     pub runs: Vec<Run>,
+    /// Decoration lines in visual order.
+    pub decorations: Vec<DecorationSegment>,
+}
+
+/// Decoration line along part of a line of text.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DecorationSegment {
+    pub kind: DecorationKind,
+    pub style: DecorationStyle,
+    /// Offset of the left edge of the segment from the start of the line.
+    pub x: f32,
+    pub width: f32,
+    /// Distance from the baseline to the top of the line (positive is up).
+    pub offset: f32,
+    pub thickness: f32,
+    pub color: [f32; 4],
+}
+
+struct Span {
+    style: Style,
+    range: Range<usize>,
+}
+
+/// Clusters of a measured paragraph without glyphs.
+struct MeasuredParagraph {
+    clusters: Vec<Cluster>,
+    /// Ascent and descent of each item.
+    item_metrics: Vec<(f32, f32)>,
+}
+
+/// Paragraph waiting for layout along with the settings that were current
+/// when it was finished.
+struct Paragraph {
+    text: String,
+    spans: Vec<Span>,
+    direction: Direction,
+    alignment: Alignment,
+}
+
+/// Range of text with a single script, bidi level and font whose spans
+/// are all shaped alike.
+struct Item {
+    /// First span of the item.
+    span: usize,
+    script: Script,
+    level: u8,
+    /// Index of the font in the span's fallback list.
+    font: usize,
+    range: Range<usize>,
+}
+
+struct Cluster {
+    item: usize,
+    /// Span of the first character of the cluster.
+    span: usize,
+    source: Range<usize>,
+    glyphs: Range<usize>,
+    advance: f32,
+    is_whitespace: bool,
+    boundary: Boundary,
+}
+
+/// Deferred alignment of a line. Applied in `build` once the width of the
+/// widest line is known.
+struct LineAlignment {
+    /// Fraction of the free space placed before the line.
+    factor: f32,
+    /// Width of trailing whitespace that is placed visually before the
+    /// line in right-to-left paragraphs.
+    leading_space: f32,
+}
+
+/// Contiguous clusters on a line that share an item, span and level.
+struct Segment {
+    item: usize,
+    span: usize,
+    level: u8,
+    clusters: Range<usize>,
 }
 
 impl TextBuilder {
     pub fn new(max_width: Option<f32>) -> Self {
         Self {
+            shape_ctx: ShapeContext::new(),
+            scale_ctx: ScaleContext::new(),
             max_width: max_width.unwrap_or(f32::MAX),
-            x: 0.0,
+            max_lines: usize::MAX,
+            max_height: f32::MAX,
+            overflow: Overflow::Clip,
             y: 0.0,
-            lines: vec![Line::default()],
-        }
-    }
-
-    pub fn add_text(mut self, font: &Font, font_size: f32, color: [f32; 4], text: &str) -> Self {
-        let charmap = font.as_ref().charmap();
-        let glyph_metrics = font.as_ref().glyph_metrics(&[]).scale(font_size);
-        let metrics = font.as_ref().metrics(&[]).scale(font_size);
-        let ascent = metrics.ascent;
-        let descent = metrics.descent;
-        let mut ids = vec![];
-        let mut advances = vec![];
-        for ch in text.chars() {
-            let id = charmap.map(ch);
-            let advance = glyph_metrics.advance_width(id);
-            let mut end = self.x + advance;
-            let mut line = self.lines.last_mut().unwrap();
-            if ch == '\n' || end > self.max_width {
-                line.runs.push(Run {
+            offset: 0,
+            lines: vec![],
+            line_alignments: vec![],
+            direction: Direction::Auto,
+            alignment: Alignment::Start,
+            synthesis: Synthesis::default(),
+            decoration: Decoration::default(),
+            paragraphs: vec![],
+            text: String::new(),
+            spans: vec![],
+        }
+    }
+
+    /// Sets the base direction for the current and all following
+    /// paragraphs.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Sets the alignment for the current and all following paragraphs.
+    pub fn align(mut self, alignment: Alignment) -> Self {
+        self.alignment = alignment;
+        self
+    }
+
+    /// Sets the maximum number of lines in the text.
+    pub fn max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = max_lines;
+        self
+    }
+
+    /// Sets the maximum height of the text. Lines that extend past it are
+    /// dropped.
+    pub fn max_height(mut self, max_height: f32) -> Self {
+        self.max_height = max_height;
+        self
+    }
+
+    /// Sets the handling of text that exceeds the limits.
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Sets the synthetic styles for all following spans.
+    pub fn synthesize(mut self, synthesis: Synthesis) -> Self {
+        self.synthesis = synthesis;
+        self
+    }
+
+    /// Sets the decoration lines for all following spans.
+    pub fn decorate(mut self, decoration: Decoration) -> Self {
+        self.decoration = decoration;
+        self
+    }
+
+    /// Adds a span of text. Characters missing from the primary font are
+    /// rendered with the first font in the fallback list that supports them.
+    pub fn add_text(
+        mut self,
+        fonts: impl Into<FontFallback>,
+        font_size: f32,
+        color: [f32; 4],
+        text: &str,
+    ) -> Self {
+        let style = Style {
+            synthesis: self.synthesis,
+            decoration: self.decoration,
+            ..Style::new(fonts, font_size, color)
+        };
+        self.push_text(text, &style);
+        self
+    }
+
+    /// Adds a string with a base style and attributes that override the
+    /// style for byte ranges of the string. Where ranges overlap, later
+    /// attributes take precedence. Range bounds inside a character are
+    /// widened to cover the whole character and empty ranges are ignored.
+    ///
+    /// The string is laid out as a whole, so attributes that don't affect
+    /// shaping, like colors and decorations, can change without breaking
+    /// ligatures or kerning. A cluster takes the style of its first
+    /// character.
+    pub fn add_attributed_text(
+        mut self,
+        text: &str,
+        style: &Style,
+        attributes: &[(Range<usize>, Attribute)],
+    ) -> Self {
+        let start_of_char = |mut offset: usize| {
+            offset = offset.min(text.len());
+            while !text.is_char_boundary(offset) {
+                offset -= 1;
+            }
+            offset
+        };
+        let end_of_char = |mut offset: usize| {
+            offset = offset.min(text.len());
+            while !text.is_char_boundary(offset) {
+                offset += 1;
+            }
+            offset
+        };
+        let attributes: Vec<_> = attributes
+            .iter()
+            .filter(|(range, _)| range.start < range.end)
+            .map(|(range, attribute)| {
+                (
+                    start_of_char(range.start)..end_of_char(range.end),
+                    attribute,
+                )
+            })
+            .collect();
+        let mut boundaries = vec![0, text.len()];
+        for (range, _) in &attributes {
+            boundaries.push(range.start);
+            boundaries.push(range.end);
+        }
+        boundaries.sort_unstable();
+        boundaries.dedup();
+        for range in boundaries.windows(2) {
+            let (start, end) = (range[0], range[1]);
+            let mut range_style = style.clone();
+            for (range, attribute) in &attributes {
+                if range.start <= start && range.end >= end {
+                    range_style.apply(attribute);
+                }
+            }
+            self.push_text(&text[start..end], &range_style);
+        }
+        self
+    }
+
+    /// Appends text with a single style to the current paragraph, finishing
+    /// paragraphs at newlines.
+    fn push_text(&mut self, text: &str, style: &Style) {
+        let push_span = |builder: &mut Self, text: &str| {
+            let start = builder.text.len();
+            builder.text.push_str(text);
+            builder.spans.push(Span {
+                style: style.clone(),
+                range: start..builder.text.len(),
+            });
+        };
+        let mut rest = text;
+        while let Some(pos) = rest.find('\n') {
+            push_span(self, &rest[..pos]);
+            self.end_paragraph();
+            rest = &rest[pos + 1..];
+        }
+        push_span(self, rest);
+    }
+
+    /// Moves the text and spans of the current paragraph into the list of
+    /// paragraphs waiting for layout.
+    fn end_paragraph(&mut self) {
+        self.paragraphs.push(Paragraph {
+            text: std::mem::take(&mut self.text),
+            spans: std::mem::take(&mut self.spans),
+            direction: self.direction,
+            alignment: self.alignment,
+        });
+    }
+
+    /// Measures the text added so far without building render ready
+    /// output. The text is cleared while the settings and shaping caches are
+    /// kept, so one builder can measure a batch of texts.
+    pub fn measure(&mut self) -> Measurement {
+        if !self.spans.is_empty() {
+            self.end_paragraph();
+        }
+        let paragraphs = std::mem::take(&mut self.paragraphs)
+            .iter()
+            .map(|paragraph| {
+                let (items, _, clusters) = self.shape_paragraph(paragraph, None);
+                let mut item_metrics: Vec<_> = items
+                    .iter()
+                    .map(|item| {
+                        let span = &paragraph.spans[item.span];
+                        font_metrics(&span.style.fonts.fonts()[item.font], span.style.font_size)
+                    })
+                    .collect();
+                // Empty paragraphs have no items and take their height from
+                // the primary fonts of all spans, as in `layout_paragraph`.
+                if clusters.is_empty() {
+                    item_metrics.extend(paragraph.spans.iter().map(|span| {
+                        font_metrics(span.style.fonts.primary(), span.style.font_size)
+                    }));
+                }
+                MeasuredParagraph {
+                    clusters,
+                    item_metrics,
+                }
+            })
+            .collect();
+        Measurement {
+            paragraphs,
+            wraps: !matches!(
+                self.overflow,
+                Overflow::MiddleEllipsis | Overflow::StartEllipsis
+            ),
+            max_lines: self.max_lines,
+            max_height: self.max_height,
+        }
+    }
+
+    pub fn build(mut self) -> Text {
+        if !self.spans.is_empty() {
+            self.end_paragraph();
+        }
+        for (i, paragraph) in std::mem::take(&mut self.paragraphs).iter().enumerate() {
+            // Account for the newline between paragraphs.
+            if i > 0 {
+                self.offset += 1;
+            }
+            self.layout_paragraph(paragraph);
+        }
+        let max_height = self.max_height;
+        let visible_lines = self
+            .lines
+            .iter()
+            .take(self.max_lines)
+            .take_while(|line| line.y + line.ascent + line.descent <= max_height)
+            .count();
+        let mut truncated = visible_lines < self.lines.len();
+        self.lines.truncate(visible_lines);
+        self.line_alignments.truncate(visible_lines);
+        match self.overflow {
+            Overflow::Clip => {}
+            Overflow::EndEllipsis => {
+                if truncated && visible_lines > 0 {
+                    self.ellipsize(visible_lines - 1);
+                }
+            }
+            Overflow::MiddleEllipsis | Overflow::StartEllipsis => {
+                for index in 0..self.lines.len() {
+                    if self.lines[index].width > self.max_width {
+                        self.ellipsize(index);
+                        truncated = true;
+                    }
+                }
+            }
+        }
+        // Without a maximum width, lines are aligned relative to the
+        // widest line.
+        let width = if self.max_width < f32::MAX {
+            self.max_width
+        } else {
+            self.lines.iter().map(|line| line.width).fold(0.0, f32::max)
+        };
+        for (line, alignment) in self.lines.iter_mut().zip(&self.line_alignments) {
+            line.x = (width - line.width) * alignment.factor - alignment.leading_space;
+        }
+        let visible_range = match (self.lines.first(), self.lines.last()) {
+            (Some(first), Some(last)) => first.range.start..last.range.end,
+            _ => 0..0,
+        };
+        Text {
+            lines: self.lines,
+            truncated,
+            visible_range,
+        }
+    }
+
+    /// Replaces clusters at the start, middle or end of a line, depending
+    /// on the overflow mode, with an ellipsis so that the line fits within
+    /// the maximum width. With an end ellipsis, the ellipsis is added even
+    /// if the line already fits.
+    fn ellipsize(&mut self, index: usize) {
+        let line = &mut self.lines[index];
+        // Clusters of the line in logical order along with their runs.
+        let mut clusters: Vec<(&Run, &RunCluster)> = line
+            .runs
+            .iter()
+            .flat_map(|run| run.clusters.iter().map(move |cluster| (run, cluster)))
+            .collect();
+        clusters.sort_by_key(|(_, cluster)| cluster.source.start);
+        let len = clusters.len();
+        if len == 0 {
+            return;
+        }
+        // The ellipsis takes the style of the run next to the elided text.
+        let style_run = match self.overflow {
+            Overflow::StartEllipsis => clusters[0].0,
+            Overflow::MiddleEllipsis => clusters[len / 2].0,
+            _ => clusters[len - 1].0,
+        };
+        let (mut glyphs, mut ellipsis_width) = shape_ellipsis(&mut self.shape_ctx, style_run);
+        let available = self.max_width - ellipsis_width;
+        // Without room for the whole ellipsis, no text is kept and the
+        // ellipsis is clipped to the glyphs that fit.
+        if available < 0.0 {
+            let max_width = self.max_width;
+            let mut width = 0.0;
+            glyphs.retain(|glyph| {
+                width += glyph.advance;
+                width <= max_width
+            });
+            ellipsis_width = glyphs.iter().map(|glyph| glyph.advance).sum();
+        }
+        let fit = |advances: &mut dyn Iterator<Item = f32>| {
+            let mut width = 0.0;
+            advances
+                .take_while(|advance| {
+                    width += advance;
+                    width <= available
+                })
+                .count()
+        };
+        // Number of clusters kept at the logical start and end of the line.
+        let (head, tail) = match self.overflow {
+            Overflow::StartEllipsis => (0, fit(&mut clusters.iter().rev().map(|c| c.1.advance))),
+            Overflow::MiddleEllipsis => {
+                // Alternate between both ends so that about as much of each
+                // remains.
+                let (mut head, mut tail, mut width) = (0, 0, 0.0);
+                while head + tail < len {
+                    let is_head = head <= tail;
+                    let cluster = if is_head {
+                        clusters[head].1
+                    } else {
+                        clusters[len - 1 - tail].1
+                    };
+                    width += cluster.advance;
+                    if width > available {
+                        break;
+                    }
+                    if is_head {
+                        head += 1;
+                    } else {
+                        tail += 1;
+                    }
+                }
+                (head, tail)
+            }
+            _ => (fit(&mut clusters.iter().map(|c| c.1.advance)), 0),
+        };
+        let elided_start = match head {
+            0 => line.range.start,
+            _ => clusters[head - 1].1.source.end,
+        };
+        let elided_end = match tail {
+            0 => line.range.end,
+            _ => clusters[len - tail].1.source.start,
+        };
+        let elided = elided_start..elided_end.max(elided_start);
+        // The ellipsis follows the last cluster kept at the start, or
+        // precedes the first one kept at the end, in logical order.
+        let anchor = if head > 0 {
+            Some((clusters[head - 1].1.source.start, true))
+        } else if tail > 0 {
+            Some((clusters[len - tail].1.source.start, false))
+        } else {
+            None
+        };
+        let mut ellipsis = Some(Run {
+            font: style_run.font.clone(),
+            font_size: style_run.font_size,
+            color: style_run.color,
+            synthesis: style_run.synthesis,
+            decoration: style_run.decoration,
+            is_rtl: style_run.is_rtl,
+            clusters: vec![RunCluster {
+                source: elided.clone(),
+                glyphs: 0..glyphs.len(),
+                advance: ellipsis_width,
+            }],
+            glyphs,
+        });
+        // Rebuild the runs in visual order without the elided clusters,
+        // splitting the run that holds the ellipsis.
+        let mut runs = vec![];
+        for run in &line.runs {
+            let mut kept = vec![];
+            for cluster in &run.clusters {
+                let is_anchor = anchor
+                    .as_ref()
+                    .filter(|(start, _)| *start == cluster.source.start);
+                // Logical order is reversed visually in right-to-left runs.
+                let is_before = matches!(is_anchor, Some((_, after)) if *after == run.is_rtl);
+                if is_before {
+                    runs.extend(run.with_clusters(&kept));
+                    runs.extend(ellipsis.take());
+                    kept.clear();
+                }
+                if cluster.source.start < elided.start || cluster.source.start >= elided.end {
+                    kept.push(cluster.clone());
+                }
+                if is_anchor.is_some() && !is_before {
+                    runs.extend(run.with_clusters(&kept));
+                    runs.extend(ellipsis.take());
+                    kept.clear();
+                }
+            }
+            runs.extend(run.with_clusters(&kept));
+        }
+        runs.extend(ellipsis);
+        line.runs = runs;
+        line.width = line
+            .runs
+            .iter()
+            .flat_map(|run| &run.clusters)
+            .map(|cluster| cluster.advance)
+            .sum();
+        match self.overflow {
+            Overflow::StartEllipsis => line.range.start = elided.end,
+            Overflow::MiddleEllipsis => {}
+            _ => line.range.end = elided.start,
+        }
+        line.elided = Some(elided);
+        // Trailing whitespace is part of the width now.
+        self.line_alignments[index].leading_space = 0.0;
+        line.decorations.clear();
+        let mut run_x = 0.0;
+        for run in &line.runs {
+            let run_width: f32 = run.clusters.iter().map(|cluster| cluster.advance).sum();
+            if !run.decoration.is_empty() {
+                decorate_run(
+                    &mut self.scale_ctx,
+                    run,
+                    run_x,
+                    run_x..run_x + run_width,
+                    &mut line.decorations,
+                );
+            }
+            run_x += run_width;
+        }
+    }
+
+    /// Returns the width at which lines are wrapped. Paragraphs aren't
+    /// wrapped when the start or middle is elided.
+    fn wrap_width(&self) -> f32 {
+        match self.overflow {
+            Overflow::MiddleEllipsis | Overflow::StartEllipsis => f32::MAX,
+            _ => self.max_width,
+        }
+    }
+
+    /// Shapes a paragraph and returns its items, base level and clusters in
+    /// logical order. Glyphs are only kept if a vector is given for them.
+    fn shape_paragraph(
+        &mut self,
+        paragraph: &Paragraph,
+        mut glyphs: Option<&mut Vec<ShapedGlyph>>,
+    ) -> (Vec<Item>, u8, Vec<Cluster>) {
+        let default_level = match paragraph.direction {
+            Direction::Auto => None,
+            Direction::LeftToRight => Some(Level::ltr()),
+            Direction::RightToLeft => Some(Level::rtl()),
+        };
+        let bidi = BidiInfo::new(&paragraph.text, default_level);
+        let base_level = bidi
+            .paragraphs
+            .first()
+            .map(|para| para.level)
+            .or(default_level)
+            .unwrap_or_else(Level::ltr)
+            .number();
+        let items = paragraph.itemize(&bidi.levels);
+        // Boundary analysis runs over the whole paragraph so that break
+        // opportunities are found across spans.
+        let infos: Vec<CharInfo> = analyze(paragraph.text.chars())
+            .map(|(props, boundary)| CharInfo::new(props, boundary))
+            .collect();
+        let mut char_index = 0;
+        // Shape each item, keeping clusters in logical order. Visual
+        // reordering happens per line after breaking.
+        let mut clusters = vec![];
+        let mut char_cluster = CharCluster::new();
+        for (index, item) in items.iter().enumerate() {
+            let span = &paragraph.spans[item.span];
+            let font = &span.style.fonts.fonts()[item.font];
+            let charmap = font.as_ref().charmap();
+            let direction = if item.level & 1 != 0 {
+                swash::shape::Direction::RightToLeft
+            } else {
+                swash::shape::Direction::LeftToRight
+            };
+            let mut shaper = self
+                .shape_ctx
+                .builder(font.as_ref())
+                .script(item.script)
+                .direction(direction)
+                .size(span.style.font_size)
+                .features(span.style.features.iter().copied())
+                .variations(font.variations.iter().map(|var| (var.tag, var.value)))
+                .build();
+            let spans = &paragraph.spans;
+            let item_text = &paragraph.text[item.range.clone()];
+            let item_infos = &infos[char_index..];
+            char_index += item_text.chars().count();
+            let is_rtl = item.level & 1 != 0;
+            let mut parser = Parser::new(
+                item.script,
+                item_text
+                    .char_indices()
+                    .zip(item_infos)
+                    .map(|((i, ch), info)| Token {
+                        // Characters with mirrored forms, like brackets, are
+                        // replaced in right-to-left runs.
+                        ch: if is_rtl {
+                            ch.mirror().unwrap_or(ch)
+                        } else {
+                            ch
+                        },
+                        offset: (item.range.start + i) as u32,
+                        len: ch.len_utf8() as u8,
+                        info: *info,
+                        data: 0,
+                    }),
+            );
+            while parser.next(&mut char_cluster) {
+                char_cluster.map(|ch| charmap.map(ch));
+                shaper.add_cluster(&char_cluster);
+            }
+            // Emboldened glyphs grow to the right, so spacing glyphs are
+            // widened by the same amount.
+            let embolden = span.style.synthesis.embolden_strength(span.style.font_size) * 2.0;
+            let widen = |advance: f32| {
+                if advance != 0.0 {
+                    advance + embolden
+                } else {
+                    advance
+                }
+            };
+            shaper.shape_with(|cluster| {
+                let glyph_range = match glyphs.as_deref_mut() {
+                    Some(glyphs) => {
+                        let start = glyphs.len();
+                        glyphs.extend(cluster.glyphs.iter().map(|glyph| ShapedGlyph {
+                            id: glyph.id,
+                            x: glyph.x,
+                            y: glyph.y,
+                            advance: widen(glyph.advance),
+                        }));
+                        start..glyphs.len()
+                    }
+                    None => 0..0,
+                };
+                clusters.push(Cluster {
+                    item: index,
+                    span: span_at(spans, cluster.source.start as usize),
+                    source: cluster.source.to_range(),
+                    glyphs: glyph_range,
+                    advance: cluster
+                        .glyphs
+                        .iter()
+                        .map(|glyph| widen(glyph.advance))
+                        .sum(),
+                    is_whitespace: cluster.info.is_whitespace(),
+                    boundary: cluster.info.boundary(),
+                });
+            });
+            // Oblique glyphs lean past their advance, so leave room for the
+            // slant where an oblique span is followed by an upright one.
+            // Synthesis affects shaping, so such a change ends the item.
+            let next = spans.get(span_at(spans, item.range.end));
+            let next_is_upright = matches!(next, Some(next) if !next.style.synthesis.oblique);
+            if span.style.synthesis.oblique && !is_rtl && next_is_upright {
+                let correction = oblique_correction(font, span.style.font_size);
+                if let Some(cluster) = clusters.last_mut().filter(|c| c.item == index) {
+                    cluster.advance += correction;
+                    let glyph = glyphs
+                        .as_deref_mut()
+                        .and_then(|glyphs| glyphs[cluster.glyphs.clone()].last_mut());
+                    if let Some(glyph) = glyph {
+                        glyph.advance += correction;
+                    }
+                }
+            }
+        }
+        (items, base_level, clusters)
+    }
+
+    /// Shapes a paragraph, breaks it into lines and appends them.
+    fn layout_paragraph(&mut self, paragraph: &Paragraph) {
+        let mut glyphs = vec![];
+        let (items, base_level, mut clusters) = self.shape_paragraph(paragraph, Some(&mut glyphs));
+        let line_ranges = break_lines(&clusters, self.wrap_width());
+        let is_rtl = base_level & 1 != 0;
+        if paragraph.alignment == Alignment::Justify && self.max_width < f32::MAX {
+            for (i, range) in line_ranges.iter().enumerate() {
+                let is_last = i + 1 == line_ranges.len()
+                    || clusters[range.end].boundary == Boundary::Mandatory;
+                if !is_last {
+                    justify(&mut clusters[range.clone()], &mut glyphs, self.max_width);
+                }
+            }
+        }
+        let factor = match (paragraph.alignment, is_rtl) {
+            (Alignment::Center, _) => 0.5,
+            (Alignment::End, false) | (Alignment::Start, true) | (Alignment::Justify, true) => 1.0,
+            _ => 0.0,
+        };
+        for range in line_ranges {
+            let line_clusters = &clusters[range];
+            // L1: trailing whitespace is reset to the paragraph level.
+            let trailing = visible_len(line_clusters);
+            let width = line_clusters[..trailing]
+                .iter()
+                .map(|cluster| cluster.advance)
+                .sum();
+            let trailing_width = line_clusters[trailing..]
+                .iter()
+                .map(|cluster| cluster.advance)
+                .sum();
+            self.line_alignments.push(LineAlignment {
+                factor,
+                leading_space: if is_rtl { trailing_width } else { 0.0 },
+            });
+            let mut segments: Vec<Segment> = vec![];
+            for (i, cluster) in line_clusters.iter().enumerate() {
+                let level = if i >= trailing {
+                    base_level
+                } else {
+                    items[cluster.item].level
+                };
+                match segments.last_mut() {
+                    Some(segment)
+                        if segment.item == cluster.item
+                            && segment.span == cluster.span
+                            && segment.level == level =>
+                    {
+                        segment.clusters.end = i + 1;
+                    }
+                    _ => segments.push(Segment {
+                        item: cluster.item,
+                        span: cluster.span,
+                        level,
+                        clusters: i..i + 1,
+                    }),
+                }
+            }
+            reorder_segments(&mut segments);
+            let text_range = match (line_clusters.first(), line_clusters.last()) {
+                (Some(first), Some(last)) => first.source.start..last.source.end,
+                _ => 0..0,
+            };
+            let mut line = Line {
+                y: self.y,
+                width,
+                range: self.offset + text_range.start..self.offset + text_range.end,
+                ..Default::default()
+            };
+            if segments.is_empty() {
+                for span in &paragraph.spans {
+                    let (ascent, descent) =
+                        font_metrics(span.style.fonts.primary(), span.style.font_size);
+                    line.ascent = line.ascent.max(ascent);
+                    line.descent = line.descent.max(descent);
+                }
+            }
+            // Offset of the current run from the start of the line.
+            let mut run_x = 0.0;
+            for segment in segments {
+                let item = &items[segment.item];
+                // Spans within an item only differ in attributes that don't
+                // affect shaping, such as colors.
+                let span = &paragraph.spans[segment.span];
+                let font = &span.style.fonts.fonts()[item.font];
+                let (ascent, descent) = font_metrics(font, span.style.font_size);
+                line.ascent = line.ascent.max(ascent);
+                line.descent = line.descent.max(descent);
+                let is_rtl = segment.level & 1 != 0;
+                let visible =
+                    segment.clusters.start.min(trailing)..segment.clusters.end.min(trailing);
+                let visible_width: f32 = line_clusters[visible]
+                    .iter()
+                    .map(|cluster| cluster.advance)
+                    .sum();
+                let segment_clusters = &line_clusters[segment.clusters];
+                let len = segment_clusters.len();
+                let mut run_glyphs = vec![];
+                let mut run_clusters = Vec::with_capacity(len);
+                for i in 0..len {
+                    // Reverse clusters, not glyphs, for right-to-left runs
+                    // so that marks stay attached to their bases.
+                    let cluster = if is_rtl {
+                        &segment_clusters[len - 1 - i]
+                    } else {
+                        &segment_clusters[i]
+                    };
+                    let start = run_glyphs.len();
+                    run_glyphs.extend_from_slice(&glyphs[cluster.glyphs.clone()]);
+                    run_clusters.push(RunCluster {
+                        source: self.offset + cluster.source.start
+                            ..self.offset + cluster.source.end,
+                        glyphs: start..run_glyphs.len(),
+                        advance: cluster.advance,
+                    });
+                }
+                let run = Run {
                     font: font.clone(),
-                    font_size,
+                    font_size: span.style.font_size,
+                    color: span.style.color,
+                    synthesis: span.style.synthesis,
+                    decoration: span.style.decoration,
+                    is_rtl,
+                    glyphs: run_glyphs,
+                    clusters: run_clusters,
+                };
+                let run_width: f32 = run.clusters.iter().map(|cluster| cluster.advance).sum();
+                if !span.style.decoration.is_empty() && visible_width > 0.0 {
+                    // Decorations don't extend over trailing whitespace,
+                    // which is at the visual end of the run.
+                    let extent = if is_rtl {
+                        run_x + run_width - visible_width..run_x + run_width
+                    } else {
+                        run_x..run_x + visible_width
+                    };
+                    decorate_run(
+                        &mut self.scale_ctx,
+                        &run,
+                        run_x,
+                        extent,
+                        &mut line.decorations,
+                    );
+                }
+                run_x += run_width;
+                line.runs.push(run);
+            }
+            self.y = (line.y + line.ascent + line.descent).round();
+            self.lines.push(line);
+        }
+        self.offset += paragraph.text.len();
+    }
+}
+
+impl Paragraph {
+    /// Splits the paragraph into items at script, bidi level and
+    /// font boundaries and where the spans are shaped differently.
+    fn itemize(&self, levels: &[Level]) -> Vec<Item> {
+        let mut items = vec![];
+        let span_at = |offset: usize| span_at(&self.spans, offset);
+        for (script, range) in script_runs(&self.text) {
+            let mut start = range.start;
+            for (i, _) in self.text[range.clone()].char_indices() {
+                let i = range.start + i;
+                let is_shaped_alike = self.spans[span_at(i)]
+                    .style
+                    .is_shaped_like(&self.spans[span_at(start)].style);
+                if i > start && (!is_shaped_alike || levels[i] != levels[start]) {
+                    let level = levels[start].number();
+                    self.push_items(&mut items, span_at(start), script, level, start..i);
+                    start = i;
+                }
+            }
+            let level = levels[start].number();
+            self.push_items(&mut items, span_at(start), script, level, start..range.end);
+        }
+        items
+    }
+
+    /// Pushes items for a range with a single span, script and level,
+    /// splitting where the font selected from the fallback list changes.
+    fn push_items(
+        &self,
+        items: &mut Vec<Item>,
+        span: usize,
+        script: Script,
+        level: u8,
+        range: Range<usize>,
+    ) {
+        let fonts = &self.spans[span].style.fonts;
+        let mut cluster = CharCluster::new();
+        let mut parser = Parser::new(
+            script,
+            self.text[range.clone()]
+                .char_indices()
+                .map(|(i, ch)| Token {
+                    ch,
+                    offset: (range.start + i) as u32,
+                    len: ch.len_utf8() as u8,
+                    info: ch.into(),
+                    data: 0,
+                }),
+        );
+        let mut start = range.start;
+        let mut font = None;
+        while parser.next(&mut cluster) {
+            let cluster_font = fonts.select(&mut cluster);
+            match font {
+                Some(font) if font != cluster_font => {
+                    let offset = cluster.range().start as usize;
+                    items.push(Item {
+                        span,
+                        script,
+                        level,
+                        font,
+                        range: start..offset,
+                    });
+                    start = offset;
+                }
+                _ => {}
+            }
+            font = Some(cluster_font);
+        }
+        items.push(Item {
+            span,
+            script,
+            level,
+            font: font.unwrap_or(0),
+            range: start..range.end,
+        });
+    }
+}
+
+/// Returns the index of the span containing the offset in the current
+/// paragraph.
+fn span_at(spans: &[Span], offset: usize) -> usize {
+    spans.partition_point(|span| span.range.end <= offset)
+}
+
+/// Logical side of a cluster that a caret position is associated with.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Affinity {
+    /// The caret is at the start of the cluster.
+    Leading,
+    /// The caret is at the end of the cluster.
+    Trailing,
+}
+
+/// Result of a hit test.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Hit {
+    /// Byte offset of the caret position in the source text.
+    pub offset: usize,
+    pub affinity: Affinity,
+}
+
+impl Text {
+    /// Returns the caret position nearest to the specified point, relative
+    /// to the origin of the text.
+    pub fn hit_test(&self, x: f32, y: f32) -> Hit {
+        let line = match self
+            .lines
+            .iter()
+            .find(|line| y < line.y + line.ascent + line.descent)
+            .or_else(|| self.lines.last())
+        {
+            Some(line) => line,
+            None => {
+                return Hit {
+                    offset: 0,
+                    affinity: Affinity::Leading,
+                }
+            }
+        };
+        let clusters = line.visual_clusters();
+        // Points outside of the line snap to the nearest cluster.
+        let hit = clusters
+            .iter()
+            .find(|(x0, _, cluster)| x < x0 + cluster.advance)
+            .or_else(|| clusters.last());
+        match hit {
+            Some(&(x0, is_rtl, cluster)) => {
+                let is_leading = (x < x0 + cluster.advance * 0.5) != is_rtl;
+                if is_leading {
+                    Hit {
+                        offset: cluster.source.start,
+                        affinity: Affinity::Leading,
+                    }
+                } else {
+                    Hit {
+                        offset: cluster.source.end,
+                        affinity: Affinity::Trailing,
+                    }
+                }
+            }
+            None => Hit {
+                offset: line.range.start,
+                affinity: Affinity::Leading,
+            },
+        }
+    }
+
+    /// Returns the rectangle (as `[x0, y0, x1, y1]`) of a one unit wide
+    /// caret at the specified source offset. The affinity selects between
+    /// the two positions of an offset at a soft line break or a bidi
+    /// boundary: a trailing caret follows the preceding character, so at a
+    /// soft line break it stays at the end of the upper line, while a
+    /// leading caret precedes the following character.
+    pub fn caret_rect(&self, offset: usize, affinity: Affinity) -> Option<[f32; 4]> {
+        // Whether the offset touches the source range on the side given by
+        // the affinity.
+        let touches = |offset: usize, range: &Range<usize>, affinity| match affinity {
+            Affinity::Leading => offset >= range.start && offset < range.end,
+            Affinity::Trailing => offset > range.start && offset <= range.end,
+        };
+        let other = match affinity {
+            Affinity::Leading => Affinity::Trailing,
+            Affinity::Trailing => Affinity::Leading,
+        };
+        let line = self
+            .lines
+            .iter()
+            .find(|line| touches(offset, &line.range, affinity))
+            .or_else(|| {
+                self.lines
+                    .iter()
+                    .find(|line| touches(offset, &line.range, other))
+            })
+            // Empty lines only touch offsets with neither affinity.
+            .or_else(|| self.lines.iter().find(|line| line.range.start == offset))
+            .or_else(|| self.lines.last())?;
+        let offset = offset.max(line.range.start).min(line.range.end);
+        let clusters = line.visual_clusters();
+        let find_x = |affinity: Affinity| {
+            clusters.iter().find_map(|&(x0, is_rtl, cluster)| {
+                let source = &cluster.source;
+                if !touches(offset, source, affinity) {
+                    return None;
+                }
+                // Interpolate for offsets inside of ligatures.
+                let t = (offset - source.start) as f32 / source.len() as f32;
+                let t = if is_rtl { 1.0 - t } else { t };
+                Some(x0 + cluster.advance * t)
+            })
+        };
+        let x = find_x(affinity).or_else(|| find_x(other)).unwrap_or(line.x);
+        Some([x, line.y, x + 1.0, line.y + line.ascent + line.descent])
+    }
+
+    /// Returns the rectangles (as `[x0, y0, x1, y1]`) covering the
+    /// specified range of the source text. Produces more than one
+    /// rectangle per line when the range is split by bidi reordering. The range
+    /// is logical, so the rectangles don't depend on the affinity of its
+    /// endpoints. Use `caret_rect` with the affinities from `hit_test` to
+    /// place carets at the ends.
+    pub fn selection_rects(&self, range: Range<usize>) -> Vec<[f32; 4]> {
+        let mut rects = vec![];
+        for line in &self.lines {
+            if range.end <= line.range.start || range.start >= line.range.end {
+                continue;
+            }
+            let y0 = line.y;
+            let y1 = line.y + line.ascent + line.descent;
+            let mut current: Option<[f32; 4]> = None;
+            for (x0, is_rtl, cluster) in line.visual_clusters() {
+                let source = &cluster.source;
+                let start = range.start.max(source.start);
+                let end = range.end.min(source.end);
+                if start >= end {
+                    rects.extend(current.take());
+                    continue;
+                }
+                let len = source.len() as f32;
+                let t0 = (start - source.start) as f32 / len;
+                let t1 = (end - source.start) as f32 / len;
+                let (left, right) = if is_rtl {
+                    (1.0 - t1, 1.0 - t0)
+                } else {
+                    (t0, t1)
+                };
+                let left = x0 + cluster.advance * left;
+                let right = x0 + cluster.advance * right;
+                match current.as_mut() {
+                    Some(rect) if (rect[2] - left).abs() < 0.01 => rect[2] = right,
+                    _ => {
+                        rects.extend(current.take());
+                        current = Some([left, y0, right, y1]);
+                    }
+                }
+            }
+            rects.extend(current);
+        }
+        rects
+    }
+}
+
+impl Measurement {
+    /// Returns the width of the widest word, which is the narrowest width
+    /// that the text can be wrapped to without breaking words. Text that
+    /// isn't wrapped, like text with a start or middle ellipsis, is as wide
+    /// as its widest line.
+    pub fn min_content_width(&self) -> f32 {
+        if !self.wraps {
+            return self.max_content_width();
+        }
+        let mut max = 0.0f32;
+        for paragraph in &self.paragraphs {
+            let mut width = 0.0;
+            for cluster in &paragraph.clusters {
+                if matches!(cluster.boundary, Boundary::Line | Boundary::Mandatory) {
+                    width = 0.0;
+                }
+                width += cluster.advance;
+                // Trailing whitespace hangs past the end of a line.
+                if !cluster.is_whitespace {
+                    max = max.max(width);
+                }
+            }
+        }
+        max
+    }
+
+    /// Returns the width of the widest line when the text isn't wrapped.
+    pub fn max_content_width(&self) -> f32 {
+        let mut max = 0.0f32;
+        for paragraph in &self.paragraphs {
+            for range in break_lines(&paragraph.clusters, f32::MAX) {
+                let clusters = &paragraph.clusters[range];
+                let width = clusters[..visible_len(clusters)]
+                    .iter()
+                    .map(|cluster| cluster.advance)
+                    .sum();
+                max = max.max(width);
+            }
+        }
+        max
+    }
+
+    /// Returns the number of lines when the text is wrapped to the width,
+    /// limited by the maximum number of lines and height of the builder.
+    pub fn line_count(&self, max_width: f32) -> usize {
+        self.layout(max_width).0
+    }
+
+    /// Returns the height of the text when wrapped to the width. This is
+    /// the offset at which a following line would start, matching the line
+    /// positions of the built text.
+    pub fn height(&self, max_width: f32) -> f32 {
+        self.layout(max_width).1
+    }
+
+    /// Breaks the text into lines as `TextBuilder::build` does and returns
+    /// the number of lines and their height.
+    fn layout(&self, max_width: f32) -> (usize, f32) {
+        let max_width = if self.wraps { max_width } else { f32::MAX };
+        let (mut count, mut y) = (0, 0.0);
+        for paragraph in &self.paragraphs {
+            for range in break_lines(&paragraph.clusters, max_width) {
+                let (ascent, descent) = paragraph.line_metrics(range);
+                if count == self.max_lines || y + ascent + descent > self.max_height {
+                    return (count, y);
+                }
+                count += 1;
+                y = (y + ascent + descent).round();
+            }
+        }
+        (count, y)
+    }
+}
+
+impl MeasuredParagraph {
+    /// Returns the ascent and descent of a line, which are the largest of
+    /// the items on it. Empty lines use all items.
+    fn line_metrics(&self, range: Range<usize>) -> (f32, f32) {
+        let max = |(ascent, descent): (f32, f32), metrics: &(f32, f32)| {
+            (ascent.max(metrics.0), descent.max(metrics.1))
+        };
+        if range.is_empty() {
+            self.item_metrics.iter().fold((0.0, 0.0), max)
+        } else {
+            self.clusters[range]
+                .iter()
+                .map(|cluster| &self.item_metrics[cluster.item])
+                .fold((0.0, 0.0), max)
+        }
+    }
+}
+
+impl Run {
+    /// Returns a copy of the run with only the specified clusters, or
+    /// `None` if there are none.
+    fn with_clusters(&self, clusters: &[RunCluster]) -> Option<Run> {
+        if clusters.is_empty() {
+            return None;
+        }
+        let mut glyphs = vec![];
+        let clusters = clusters
+            .iter()
+            .map(|cluster| {
+                let start = glyphs.len();
+                glyphs.extend_from_slice(&self.glyphs[cluster.glyphs.clone()]);
+                RunCluster {
+                    glyphs: start..glyphs.len(),
+                    ..cluster.clone()
+                }
+            })
+            .collect();
+        Some(Run {
+            font: self.font.clone(),
+            glyphs,
+            clusters,
+            ..*self
+        })
+    }
+}
+
+impl Line {
+    /// Returns the clusters of the line in visual order along with the x
+    /// position of their left edge and the direction of their run.
+    fn visual_clusters(&self) -> Vec<(f32, bool, &RunCluster)> {
+        let mut x = self.x;
+        let mut clusters = vec![];
+        for run in &self.runs {
+            for cluster in &run.clusters {
+                clusters.push((x, run.is_rtl, cluster));
+                x += cluster.advance;
+            }
+        }
+        clusters
+    }
+}
+
+fn font_metrics(font: &Font, font_size: f32) -> (f32, f32) {
+    let metrics = font
+        .as_ref()
+        .metrics(&font.normalized_coords())
+        .scale(font_size);
+    (metrics.ascent, metrics.descent)
+}
+
+/// Appends the decoration segments for the visible extent of a run to a
+/// line, merging them with the previous segment where they continue it.
+/// `x` is the offset of the run from the start of the line.
+fn decorate_run(
+    scale_ctx: &mut ScaleContext,
+    run: &Run,
+    x: f32,
+    extent: Range<f32>,
+    segments: &mut Vec<DecorationSegment>,
+) {
+    let decoration = &run.decoration;
+    let font = &run.font;
+    let metrics = font
+        .as_ref()
+        .metrics(&font.normalized_coords())
+        .scale(run.font_size);
+    let thickness = metrics.stroke_size;
+    let color = decoration.color.unwrap_or(run.color);
+    let lines = [
+        (
+            decoration.underline,
+            DecorationKind::Underline,
+            metrics.underline_offset,
+        ),
+        (
+            decoration.strikethrough,
+            DecorationKind::Strikethrough,
+            metrics.strikeout_offset,
+        ),
+        (
+            decoration.overline,
+            DecorationKind::Overline,
+            metrics.ascent,
+        ),
+    ];
+    for (enabled, kind, offset) in lines {
+        if !enabled {
+            continue;
+        }
+        let gaps = if kind == DecorationKind::Underline && decoration.skip_ink {
+            // The band extends below the line to keep some space between it
+            // and nearby ink, but not above it, where glyphs sit on the
+            // baseline.
+            let band = offset - thickness * 2.0..offset;
+            ink_ranges(scale_ctx, run, x, band, thickness)
+        } else {
+            vec![]
+        };
+        let mut start = extent.start;
+        for gap in gaps.iter().chain(Some(&(extent.end..extent.end))) {
+            let end = gap.start.min(extent.end);
+            if end > start {
+                let segment = DecorationSegment {
+                    kind,
+                    style: decoration.style,
+                    x: start,
+                    width: end - start,
+                    offset,
+                    thickness,
                     color,
-                    ids: ids.clone(),
-                    advances: advances.clone(),
-                });
-                ids.clear();
-                advances.clear();
-                self.x = 0.0;
-                self.y = (line.y + line.ascent + line.descent).round();
-                self.lines.push(Line {
-                    y: self.y,
-                    ..Default::default()
-                });
-                line = self.lines.last_mut().unwrap();
-                end = advance;
+                };
+                match segments.last_mut() {
+                    Some(last) if continues(last, &segment) => last.width += segment.width,
+                    _ => segments.push(segment),
+                }
             }
-            line.ascent = line.ascent.max(ascent);
-            line.descent = line.descent.max(descent);
-            if ch == '\n' {
+            start = start.max(gap.end);
+        }
+    }
+}
+
+/// Returns true if a segment starts where the previous one ends and draws
+/// the same kind of line.
+fn continues(prev: &DecorationSegment, segment: &DecorationSegment) -> bool {
+    prev.kind == segment.kind
+        && prev.style == segment.style
+        && prev.offset == segment.offset
+        && prev.thickness == segment.thickness
+        && prev.color == segment.color
+        && (prev.x + prev.width - segment.x).abs() < 0.01
+}
+
+/// Returns the sorted, merged horizontal ranges where the glyphs of a run
+/// have ink between two heights above the baseline, padded on each side.
+/// `x` is the offset of the run from the start of the line.
+fn ink_ranges(
+    scale_ctx: &mut ScaleContext,
+    run: &Run,
+    x: f32,
+    band: Range<f32>,
+    padding: f32,
+) -> Vec<Range<f32>> {
+    let font = &run.font;
+    let mut scaler = scale_ctx
+        .builder(font.as_ref())
+        .size(run.font_size)
+        .variations(font.variations.iter().map(|var| (var.tag, var.value)))
+        .build();
+    let transform = run.synthesis.transform();
+    let strength = run.synthesis.embolden_strength(run.font_size);
+    let mut outline = Outline::new();
+    let mut ranges: Vec<Range<f32>> = vec![];
+    let mut pen_x = x;
+    for glyph in &run.glyphs {
+        let glyph_x = pen_x + glyph.x;
+        pen_x += glyph.advance;
+        outline.clear();
+        if !scaler.scale_outline_into(glyph.id, &mut outline) {
+            continue;
+        }
+        if strength != 0.0 {
+            outline.embolden(strength, strength);
+        }
+        outline.transform(&transform);
+        // The placement is computed up front since `Mask` doesn't report
+        // the top of bottom left images that it sizes itself.
+        let (offset, placement) = Placement::compute(Origin::BottomLeft, (0, 0), &outline.bounds());
+        let (mask, _) = Mask::new(outline.path())
+            .origin(Origin::BottomLeft)
+            .size(placement.width, placement.height)
+            .offset(offset)
+            .render();
+        // Rows of the mask from the top, converted to heights above the
+        // baseline.
+        let mut min_x = i32::MAX;
+        let mut max_x = i32::MIN;
+        let width = placement.width as usize;
+        for (row, coverage) in mask.chunks(width.max(1)).enumerate() {
+            let y = placement.top as f32 - row as f32 + glyph.y;
+            if y <= band.start || y - 1.0 >= band.end {
                 continue;
             }
-            self.x = end;
-            ids.push(id);
-            advances.push(advance)
-        }
-        if !ids.is_empty() {
-            let line = self.lines.last_mut().unwrap();
-            line.ascent = line.ascent.max(ascent);
-            line.descent = line.descent.max(descent);
-            line.runs.push(Run {
-                font: font.clone(),
-                font_size,
-                color,
-                ids,
-                advances,
-            });
+            // Partially covered texels are ignored so that the overshoot of
+            // round glyphs below the baseline doesn't count as ink.
+            for (col, _) in coverage.iter().enumerate().filter(|(_, c)| **c >= 128) {
+                min_x = min_x.min(placement.left + col as i32);
+                max_x = max_x.max(placement.left + col as i32 + 1);
+            }
         }
-        self
+        if min_x < max_x {
+            let start = glyph_x + min_x as f32 - padding;
+            let end = glyph_x + max_x as f32 + padding;
+            match ranges.last_mut() {
+                Some(last) if start <= last.end => last.end = last.end.max(end),
+                _ => ranges.push(start..end),
+            }
+        }
+    }
+    ranges
+}
+
+/// Shapes an ellipsis in the style of a run, falling back to three periods
+/// if the font doesn't support the ellipsis character. Returns the glyphs
+/// and their total advance.
+fn shape_ellipsis(shape_ctx: &mut ShapeContext, run: &Run) -> (Vec<ShapedGlyph>, f32) {
+    let font = &run.font;
+    let text = if font.as_ref().charmap().map('\u{2026}') != 0 {
+        "\u{2026}"
+    } else {
+        "..."
+    };
+    let mut shaper = shape_ctx
+        .builder(font.as_ref())
+        .size(run.font_size)
+        .variations(font.variations.iter().map(|var| (var.tag, var.value)))
+        .build();
+    shaper.add_str(text);
+    let embolden = run.synthesis.embolden_strength(run.font_size) * 2.0;
+    let mut glyphs = vec![];
+    shaper.shape_with(|cluster| {
+        glyphs.extend(cluster.glyphs.iter().map(|glyph| ShapedGlyph {
+            id: glyph.id,
+            x: glyph.x,
+            y: glyph.y,
+            advance: if glyph.advance != 0.0 {
+                glyph.advance + embolden
+            } else {
+                glyph.advance
+            },
+        }));
+    });
+    let width = glyphs.iter().map(|glyph| glyph.advance).sum();
+    (glyphs, width)
+}
+
+/// Returns the distance that the top of capital letters of an oblique span
+/// lean past the advance.
+fn oblique_correction(font: &Font, font_size: f32) -> f32 {
+    let metrics = font
+        .as_ref()
+        .metrics(&font.normalized_coords())
+        .scale(font_size);
+    metrics.cap_height * OBLIQUE_ANGLE.to_radians().tan()
+}
+
+/// Splits text into ranges of a single script. Common and inherited
+/// characters are merged into the surrounding run.
+fn script_runs(text: &str) -> Vec<(Script, Range<usize>)> {
+    let mut runs = vec![];
+    let mut script = None;
+    let mut start = 0;
+    for (i, ch) in text.char_indices() {
+        match ch.script() {
+            Script::Common | Script::Inherited | Script::Unknown => {}
+            ch_script => match script {
+                None => script = Some(ch_script),
+                Some(cur) if cur != ch_script => {
+                    runs.push((cur, start..i));
+                    script = Some(ch_script);
+                    start = i;
+                }
+                _ => {}
+            },
+        }
+    }
+    if start < text.len() {
+        runs.push((script.unwrap_or(Script::Latin), start..text.len()));
+    }
+    runs
+}
+
+/// Breaks clusters into lines at line break opportunities. Trailing
+/// whitespace hangs past the maximum width and a break between clusters
+/// is only forced when a single word doesn't fit on a line.
+fn break_lines(clusters: &[Cluster], max_width: f32) -> Vec<Range<usize>> {
+    let mut lines = vec![];
+    let mut start = 0;
+    let mut x = 0.0;
+    let mut last_break = None;
+    for (i, cluster) in clusters.iter().enumerate() {
+        if i > start {
+            match cluster.boundary {
+                Boundary::Mandatory => {
+                    lines.push(start..i);
+                    start = i;
+                    x = 0.0;
+                    last_break = None;
+                }
+                Boundary::Line => last_break = Some(i),
+                _ => {}
+            }
+        }
+        if !cluster.is_whitespace && i > start && x + cluster.advance > max_width {
+            let end = last_break.take().unwrap_or(i);
+            lines.push(start..end);
+            start = end;
+            x = clusters[start..i]
+                .iter()
+                .map(|cluster| cluster.advance)
+                .sum();
+        }
+        x += cluster.advance;
     }
+    lines.push(start..clusters.len());
+    lines
+}
+
+/// Returns the number of clusters on a line, excluding trailing whitespace.
+fn visible_len(clusters: &[Cluster]) -> usize {
+    clusters
+        .iter()
+        .rposition(|cluster| !cluster.is_whitespace)
+        .map(|i| i + 1)
+        .unwrap_or(0)
+}
 
-    pub fn build(self) -> Text {
-        Text { lines: self.lines }
+/// Distributes the free space on a line across its interior whitespace
+/// clusters.
+fn justify(clusters: &mut [Cluster], glyphs: &mut [ShapedGlyph], max_width: f32) {
+    let len = visible_len(clusters);
+    let clusters = &mut clusters[..len];
+    let gaps = clusters
+        .iter()
+        .filter(|cluster| cluster.is_whitespace)
+        .count();
+    if gaps == 0 {
+        return;
+    }
+    let width: f32 = clusters.iter().map(|cluster| cluster.advance).sum();
+    let extra = (max_width - width).max(0.0) / gaps as f32;
+    for cluster in clusters.iter_mut().filter(|cluster| cluster.is_whitespace) {
+        cluster.advance += extra;
+        if let Some(glyph) = glyphs[cluster.glyphs.clone()].last_mut() {
+            glyph.advance += extra;
+        }
+    }
+}
+
+/// Applies rule L2 of the bidi algorithm: from the highest level down to
+/// the lowest odd level, reverses any sequence of segments at that level
+/// or higher.
+fn reorder_segments(segments: &mut [Segment]) {
+    let max_level = segments.iter().map(|s| s.level).max().unwrap_or(0);
+    let min_odd_level = match segments
+        .iter()
+        .map(|s| s.level)
+        .filter(|l| l & 1 != 0)
+        .min()
+    {
+        Some(level) => level,
+        None => return,
+    };
+    for level in (min_odd_level..=max_level).rev() {
+        let mut i = 0;
+        while i < segments.len() {
+            if segments[i].level >= level {
+                let start = i;
+                while i < segments.len() && segments[i].level >= level {
+                    i += 1;
+                }
+                segments[start..i].reverse();
+            } else {
+                i += 1;
+            }
+        }
     }
 }
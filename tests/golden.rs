@@ -0,0 +1,796 @@
+//! Golden image tests for layout and rasterization.
+//!
+//! Each test lays out fixed text with the bundled fonts, renders it with the
+//! software rasterizer and compares the result against a reference image in
+//! `tests/golden`. Run with `MTL_TEXT_BLESS=1` to replace the references with
+//! the current output. On failure, the actual image and a diff image are
+//! written to `golden` in the target temporary directory.
+
+use mtl_text::cpu_render::Renderer;
+use mtl_text::font::{Font, FontFallback};
+use mtl_text::glyph_cache::{Antialias, CpuTexture};
+use mtl_text::glyph_rasterizer::Format;
+use mtl_text::image::{read_png, write_png};
+use mtl_text::text::{
+    Affinity, Alignment, Attribute, Decoration, DecorationStyle, Direction, Hit, Overflow, Style,
+    Synthesis, Text, TextBuilder,
+};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Largest per channel difference that is not counted as a mismatch.
+const CHANNEL_TOLERANCE: u8 = 24;
+
+/// Fraction of pixels that may mismatch before a comparison fails.
+const PIXEL_TOLERANCE: f32 = 0.002;
+
+const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+fn font() -> Font {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fonts/DejaVuSans.ttf");
+    Font::from_file(path).expect("failed to load bundled font")
+}
+
+/// Monospace font without Hebrew, used as a primary font that needs
+/// fallback.
+fn mono_font() -> Font {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fonts/DejaVuSansMono.ttf"
+    );
+    Font::from_file(path).expect("failed to load bundled font")
+}
+
+/// Renders each text at its position into a new frame.
+fn render(width: u32, height: u32, bg_color: [f32; 4], texts: &[(f32, f32, &Text)]) -> CpuTexture {
+    let mut renderer = Renderer::new();
+    renderer.set_target_size(width, height);
+    let mut frame = renderer.new_frame(bg_color);
+    for (x, y, text) in texts {
+        frame.draw_text(*x, *y, text);
+    }
+    frame.render()
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name)
+        .with_extension("png")
+}
+
+fn output_dir() -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden")
+}
+
+/// Compares the image against the reference with the given name, or replaces
+/// the reference when blessing.
+fn check(name: &str, image: &CpuTexture) {
+    let path = golden_path(name);
+    if std::env::var_os("MTL_TEXT_BLESS").is_some() {
+        write_png(&path, image).expect("failed to write reference image");
+        return;
+    }
+    let reference = match read_png(&path) {
+        Ok(reference) => reference,
+        Err(err) => {
+            let actual = save_output(name, "actual", image);
+            panic!(
+                "missing reference image {} ({}); the output was written to {}. \
+                 Run with MTL_TEXT_BLESS=1 to create it.",
+                path.display(),
+                err,
+                actual.display()
+            );
+        }
+    };
+    if let Err(message) = compare(&reference, image) {
+        let actual = save_output(name, "actual", image);
+        let diff = save_output(name, "diff", &diff_image(&reference, image));
+        panic!(
+            "{} does not match its reference: {}\n  actual: {}\n  diff: {}",
+            name,
+            message,
+            actual.display(),
+            diff.display()
+        );
+    }
+}
+
+fn save_output(name: &str, suffix: &str, image: &CpuTexture) -> PathBuf {
+    let dir = output_dir();
+    std::fs::create_dir_all(&dir).expect("failed to create output directory");
+    let path = dir.join(format!("{}.{}.png", name, suffix));
+    write_png(&path, image).expect("failed to write output image");
+    path
+}
+
+fn compare(reference: &CpuTexture, image: &CpuTexture) -> Result<(), String> {
+    if reference.format != image.format
+        || reference.width != image.width
+        || reference.height != image.height
+    {
+        return Err(format!(
+            "expected a {}x{} image, found {}x{}",
+            reference.width, reference.height, image.width, image.height
+        ));
+    }
+    let channels = reference.stride() / reference.width.max(1) as usize;
+    let mut mismatched = 0;
+    let mut max_diff = 0;
+    for (a, b) in reference
+        .data
+        .chunks(channels)
+        .zip(image.data.chunks(channels))
+    {
+        let diff = pixel_diff(a, b);
+        max_diff = max_diff.max(diff);
+        if diff > CHANNEL_TOLERANCE {
+            mismatched += 1;
+        }
+    }
+    let pixels = reference.width as usize * reference.height as usize;
+    if mismatched as f32 > pixels as f32 * PIXEL_TOLERANCE {
+        return Err(format!(
+            "{} of {} pixels differ (largest channel difference {})",
+            mismatched, pixels, max_diff
+        ));
+    }
+    Ok(())
+}
+
+fn pixel_diff(a: &[u8], b: &[u8]) -> u8 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (*a as i32 - *b as i32).unsigned_abs() as u8)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Builds an image showing the reference faded to gray with mismatched
+/// pixels in red. Pixels within the tolerance but not identical are shown in
+/// yellow.
+fn diff_image(reference: &CpuTexture, image: &CpuTexture) -> CpuTexture {
+    let width = reference.width.max(image.width);
+    let height = reference.height.max(image.height);
+    let mut diff = CpuTexture::new(Format::Rgba8, width, height);
+    let channels = |t: &CpuTexture| t.stride() / t.width.max(1) as usize;
+    let pixel = |t: &CpuTexture, x: u32, y: u32| -> Option<Vec<u8>> {
+        if x >= t.width || y >= t.height {
+            return None;
+        }
+        let n = channels(t);
+        let offset = y as usize * t.stride() + x as usize * n;
+        let p = &t.data[offset..offset + n];
+        Some(if n == 1 {
+            vec![p[0], p[0], p[0], 255]
+        } else {
+            p.to_vec()
+        })
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let a = pixel(reference, x, y);
+            let b = pixel(image, x, y);
+            let rgba = match (a, b) {
+                (Some(a), Some(b)) => {
+                    let d = pixel_diff(&a, &b);
+                    let luma = (a[0] as u32 + a[1] as u32 + a[2] as u32) / 3;
+                    let gray = (128 + luma / 2) as u8;
+                    if d > CHANNEL_TOLERANCE {
+                        [255, 0, 0, 255]
+                    } else if d > 0 {
+                        [255, 200, 0, 255]
+                    } else {
+                        [gray, gray, gray, 255]
+                    }
+                }
+                _ => [255, 0, 255, 255],
+            };
+            let offset = (y * width + x) as usize * 4;
+            diff.data[offset..offset + 4].copy_from_slice(&rgba);
+        }
+    }
+    diff
+}
+
+#[test]
+fn paragraph_wrapping() {
+    let font = font();
+    let text = TextBuilder::new(Some(300.0))
+        .add_text(
+            &font,
+            18.0,
+            BLACK,
+            "The quick brown fox jumps over the lazy dog. Pack my box with five dozen liquor jugs.",
+        )
+        .build();
+    check(
+        "paragraph_wrapping",
+        &render(320, 100, WHITE, &[(10.0, 10.0, &text)]),
+    );
+}
+
+#[test]
+fn alignment() {
+    let font = font();
+    let alignments = [
+        Alignment::Start,
+        Alignment::Center,
+        Alignment::End,
+        Alignment::Justify,
+    ];
+    let texts = alignments
+        .iter()
+        .map(|alignment| {
+            TextBuilder::new(Some(200.0))
+                .align(*alignment)
+                .add_text(
+                    &font,
+                    14.0,
+                    BLACK,
+                    "Lines of text aligned within a fixed width box.",
+                )
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let positions = texts
+        .iter()
+        .enumerate()
+        .map(|(i, text)| (10.0, 10.0 + i as f32 * 45.0, text))
+        .collect::<Vec<_>>();
+    check("alignment", &render(220, 190, WHITE, &positions));
+}
+
+#[test]
+fn bidi() {
+    let font = font();
+    let ltr = TextBuilder::new(None)
+        .add_text(&font, 18.0, BLACK, "abc שלום עולם 123 def")
+        .build();
+    let rtl = TextBuilder::new(Some(300.0))
+        .direction(Direction::RightToLeft)
+        .align(Alignment::Start)
+        .add_text(&font, 18.0, BLACK, "مرحبا بالعالم (hello) שלום!")
+        .build();
+    check(
+        "bidi",
+        &render(320, 80, WHITE, &[(10.0, 10.0, &ltr), (10.0, 45.0, &rtl)]),
+    );
+}
+
+#[test]
+fn font_fallback() {
+    let mono = mono_font();
+    let sans = font();
+    let fonts = FontFallback::new(vec![mono.clone(), sans.clone()]);
+    let text = "mono שלום 日";
+    let built = TextBuilder::new(None)
+        .add_text(&fonts, 16.0, BLACK, text)
+        .build();
+    // Runs are split where the selected font changes.
+    for run in &built.lines[0].runs {
+        for cluster in &run.clusters {
+            let source = &text[cluster.source.clone()];
+            let is_hebrew = source
+                .chars()
+                .any(|ch| ('\u{5d0}'..='\u{5ea}').contains(&ch));
+            let expected = if is_hebrew { sans.key } else { mono.key };
+            assert_eq!(run.font.key, expected, "font of {:?}", source);
+        }
+    }
+    // Characters that no font supports use the primary font.
+    let missing = text.find('日').unwrap();
+    let run = built.lines[0]
+        .runs
+        .iter()
+        .find(|run| run.clusters.iter().any(|c| c.source.start == missing))
+        .unwrap();
+    assert_eq!(run.font.key, mono.key);
+    check(
+        "font_fallback",
+        &render(200, 40, WHITE, &[(10.0, 10.0, &built)]),
+    );
+}
+
+#[test]
+fn bidi_mirroring() {
+    let font = font();
+    let text = TextBuilder::new(None)
+        .direction(Direction::RightToLeft)
+        .add_text(&font, 18.0, BLACK, "שלום (עולם)")
+        .build();
+    let charmap = font.as_ref().charmap();
+    let glyph_for = |offset: usize| {
+        let run = text.lines[0]
+            .runs
+            .iter()
+            .find(|run| run.clusters.iter().any(|c| c.source.start == offset))
+            .unwrap();
+        let cluster = run
+            .clusters
+            .iter()
+            .find(|c| c.source.start == offset)
+            .unwrap();
+        (run.is_rtl, run.glyphs[cluster.glyphs.start].id)
+    };
+    // Brackets in right-to-left runs are shaped with their mirrored forms.
+    let open = "שלום ".len();
+    let close = "שלום (עולם".len();
+    assert_eq!(glyph_for(open), (true, charmap.map(')')));
+    assert_eq!(glyph_for(close), (true, charmap.map('(')));
+}
+
+/// Returns the visual left edge of the cluster starting at the offset on the
+/// first line.
+fn cluster_x(text: &Text, offset: usize) -> f32 {
+    let line = &text.lines[0];
+    let mut x = line.x;
+    for cluster in line.runs.iter().flat_map(|run| &run.clusters) {
+        if cluster.source.start == offset {
+            return x;
+        }
+        x += cluster.advance;
+    }
+    panic!("no cluster at {}", offset);
+}
+
+#[test]
+fn hit_testing() {
+    let font = font();
+    let text = TextBuilder::new(Some(60.0))
+        .add_text(&font, 16.0, BLACK, "Hello world")
+        .build();
+    let (first, second) = (&text.lines[0], &text.lines[1]);
+    assert_eq!(first.range, 0..6);
+    let caret_x = |offset, affinity| text.caret_rect(offset, affinity).unwrap()[0];
+    // Past the end of a wrapped line, the caret stays on that line.
+    let hit = text.hit_test(1000.0, 5.0);
+    assert_eq!(
+        hit,
+        Hit {
+            offset: 6,
+            affinity: Affinity::Trailing
+        }
+    );
+    let caret = text.caret_rect(hit.offset, hit.affinity).unwrap();
+    assert_eq!(caret[1], first.y);
+    assert!(caret[0] >= first.x + first.width);
+    // The same offset with leading affinity is at the start of the next.
+    let hit = text.hit_test(-10.0, second.y + 1.0);
+    assert_eq!(
+        hit,
+        Hit {
+            offset: 6,
+            affinity: Affinity::Leading
+        }
+    );
+    assert_eq!(
+        text.caret_rect(hit.offset, hit.affinity),
+        Some([
+            second.x,
+            second.y,
+            second.x + 1.0,
+            second.y + second.ascent + second.descent
+        ])
+    );
+    // A selection across the break has a rectangle on each line.
+    let rects = text.selection_rects(3..9);
+    assert_eq!(rects.len(), 2);
+    assert_eq!(
+        rects[0],
+        [
+            caret_x(3, Affinity::Leading),
+            first.y,
+            caret_x(6, Affinity::Trailing),
+            first.y + first.ascent + first.descent
+        ]
+    );
+    assert_eq!(rects[1][0], second.x);
+    assert_eq!(rects[1][2], caret_x(9, Affinity::Leading));
+    assert_eq!(rects[1][1], second.y);
+}
+
+#[test]
+fn bidi_hit_testing() {
+    let font = font();
+    let text = TextBuilder::new(None)
+        .direction(Direction::LeftToRight)
+        .add_text(&font, 18.0, BLACK, "abc שלום def")
+        .build();
+    let caret_x = |offset, affinity| text.caret_rect(offset, affinity).unwrap()[0];
+    // The Hebrew word occupies 4..12 and is displayed right to left, so its
+    // logical end is the left edge of the word.
+    let word_left = cluster_x(&text, 10);
+    let word_right = cluster_x(&text, 12);
+    let hit = text.hit_test(word_left + 1.0, 5.0);
+    assert_eq!(
+        hit,
+        Hit {
+            offset: 12,
+            affinity: Affinity::Trailing
+        }
+    );
+    assert_eq!(caret_x(12, Affinity::Trailing), word_left);
+    assert_eq!(caret_x(12, Affinity::Leading), word_right);
+    // The start of the word is at its right edge, after "abc ".
+    assert_eq!(caret_x(4, Affinity::Leading), word_right);
+    assert_eq!(caret_x(4, Affinity::Trailing), word_left);
+    let line = &text.lines[0];
+    let (y0, y1) = (line.y, line.y + line.ascent + line.descent);
+    assert_eq!(
+        text.selection_rects(4..12),
+        vec![[word_left, y0, word_right, y1]]
+    );
+    // A range across the direction change is split visually.
+    assert_eq!(
+        text.selection_rects(2..6),
+        vec![
+            [cluster_x(&text, 2), y0, word_left, y1],
+            [cluster_x(&text, 4), y0, word_right, y1],
+        ]
+    );
+}
+
+#[test]
+fn spans() {
+    let font = font();
+    let text = TextBuilder::new(Some(300.0))
+        .add_text(&font, 14.0, [0.8, 0.1, 0.1, 1.0], "Small red, ")
+        .add_text(&font, 24.0, [0.1, 0.5, 0.1, 1.0], "large green, ")
+        .add_text(&font, 18.0, [0.1, 0.2, 0.9, 0.5], "translucent blue.")
+        .build();
+    check(
+        "spans",
+        &render(320, 80, [1.0, 0.95, 0.8, 1.0], &[(10.0, 10.0, &text)]),
+    );
+}
+
+#[test]
+fn alpha_atlas() {
+    let font = font();
+    let text = TextBuilder::new(None)
+        .add_text(&font, 20.0, BLACK, "Glyph atlas: AVWMgjpqy 0123456789")
+        .build();
+    let mut renderer = Renderer::new();
+    renderer.set_target_size(400, 40);
+    let mut frame = renderer.new_frame(WHITE);
+    frame.draw_text(0.0, 0.0, &text);
+    frame.render();
+    assert_eq!(renderer.atlas_page_count(false), 1);
+    assert_eq!(renderer.atlas_page_count(true), 0);
+    // Small glyph sets are packed into the top left corner of the page.
+    let page = renderer.atlas_page(false, 0).unwrap();
+    let mut atlas = CpuTexture::new(Format::A8, 256, 128);
+    atlas.replace_region([0, 0, 256, 128], &page.data, page.stride());
+    check("alpha_atlas", &atlas);
+}
+
+#[test]
+fn hinting() {
+    let font = font();
+    let texts = [11.0, 12.0, 13.0]
+        .iter()
+        .map(|size| {
+            TextBuilder::new(None)
+                .add_text(&font, *size, BLACK, "Hinted interface text 0123")
+                .build()
+        })
+        .collect::<Vec<_>>();
+    let mut renderer = Renderer::new();
+    renderer.set_target_size(200, 60);
+    renderer.set_hinting(true);
+    let mut frame = renderer.new_frame(WHITE);
+    for (i, text) in texts.iter().enumerate() {
+        frame.draw_text(5.0, 5.0 + i as f32 * 18.0, text);
+    }
+    check("hinting", &frame.render());
+}
+
+#[test]
+fn subpixel_antialiasing() {
+    let font = font();
+    let text = TextBuilder::new(None)
+        .add_text(&font, 14.0, BLACK, "Subpixel RGB text")
+        .build();
+    let mut renderer = Renderer::new();
+    renderer.set_target_size(160, 30);
+    renderer.set_antialias(Antialias::SubpixelRgb);
+    let mut frame = renderer.new_frame(WHITE);
+    frame.draw_text(5.0, 5.0, &text);
+    check("subpixel_antialiasing", &frame.render());
+}
+
+#[test]
+fn synthesis() {
+    let font = font();
+    let style = |embolden, oblique| Synthesis { embolden, oblique };
+    let text = TextBuilder::new(Some(300.0))
+        .add_text(&font, 18.0, BLACK, "Regular, ")
+        .synthesize(style(true, false))
+        .add_text(&font, 18.0, BLACK, "bold, ")
+        .synthesize(style(false, true))
+        .add_text(&font, 18.0, BLACK, "oblique")
+        .synthesize(style(false, false))
+        .add_text(&font, 18.0, BLACK, " and ")
+        .synthesize(style(true, true))
+        .add_text(&font, 18.0, BLACK, "bold oblique.")
+        .build();
+    check("synthesis", &render(320, 60, WHITE, &[(10.0, 10.0, &text)]));
+}
+
+#[test]
+fn decorations() {
+    let font = font();
+    let underline = |style, skip_ink| Decoration {
+        underline: true,
+        style,
+        skip_ink,
+        ..Default::default()
+    };
+    let red = [0.9, 0.1, 0.1, 1.0];
+    let text = TextBuilder::new(Some(300.0))
+        .decorate(underline(DecorationStyle::Solid, false))
+        .add_text(&font, 18.0, BLACK, "Underlined gyp")
+        .decorate(Decoration::default())
+        .add_text(&font, 18.0, BLACK, " and ")
+        .decorate(underline(DecorationStyle::Solid, true))
+        .add_text(&font, 18.0, BLACK, "skipping gyp\n")
+        .decorate(Decoration {
+            strikethrough: true,
+            overline: true,
+            ..Default::default()
+        })
+        .add_text(&font, 18.0, BLACK, "Struck and overlined\n")
+        .decorate(underline(DecorationStyle::Double, false))
+        .add_text(&font, 18.0, BLACK, "Double")
+        .decorate(Decoration::default())
+        .add_text(&font, 18.0, BLACK, ", ")
+        .decorate(underline(DecorationStyle::Dotted, false))
+        .add_text(&font, 18.0, BLACK, "dotted")
+        .decorate(Decoration::default())
+        .add_text(&font, 18.0, BLACK, ", ")
+        .decorate(Decoration {
+            color: Some(red),
+            ..underline(DecorationStyle::Wavy, false)
+        })
+        .add_text(&font, 18.0, BLACK, "mispeled")
+        .build();
+    check(
+        "decorations",
+        &render(320, 90, WHITE, &[(10.0, 10.0, &text)]),
+    );
+}
+
+#[test]
+fn attributed_text() {
+    let font = font();
+    let text = "Colors change within AVAWAY without breaking kerning. \
+                Ligatures: office fjord, no ligatures: office fjord.";
+    let range = |s: &str| {
+        let start = text.find(s).unwrap();
+        start..start + s.len()
+    };
+    let no_liga = text.rfind("office fjord").unwrap();
+    let attributes = [
+        (range("AVAWAY"), Attribute::FontSize(22.0)),
+        (range("AWA"), Attribute::Color([0.8, 0.1, 0.1, 1.0])),
+        (
+            range("kerning"),
+            Attribute::Decoration(Decoration {
+                underline: true,
+                ..Default::default()
+            }),
+        ),
+        (
+            no_liga..no_liga + "office fjord".len(),
+            Attribute::Features(vec![("liga", 0).into()]),
+        ),
+        (
+            range("Ligatures"),
+            Attribute::Synthesis(Synthesis {
+                embolden: true,
+                oblique: false,
+            }),
+        ),
+    ];
+    let text = TextBuilder::new(Some(300.0))
+        .add_attributed_text(text, &Style::new(&font, 16.0, BLACK), &attributes)
+        .build();
+    check(
+        "attributed_text",
+        &render(320, 80, WHITE, &[(10.0, 10.0, &text)]),
+    );
+}
+
+/// Returns the source ranges of the clusters drawn in the color.
+fn clusters_with_color(text: &Text, color: [f32; 4]) -> Vec<Range<usize>> {
+    text.lines
+        .iter()
+        .flat_map(|line| &line.runs)
+        .filter(|run| run.color == color)
+        .flat_map(|run| run.clusters.iter().map(|cluster| cluster.source.clone()))
+        .collect()
+}
+
+#[test]
+fn attribute_ranges_inside_characters() {
+    let font = font();
+    let red = [1.0, 0.0, 0.0, 1.0];
+    // The range starts inside the two byte "é", which is widened to cover
+    // the whole character.
+    let text = TextBuilder::new(None)
+        .add_attributed_text(
+            "café au lait",
+            &Style::new(&font, 16.0, BLACK),
+            &[(4..5, Attribute::Color(red))],
+        )
+        .build();
+    assert_eq!(clusters_with_color(&text, red), vec![3..5]);
+}
+
+#[test]
+fn empty_attribute_ranges() {
+    let font = font();
+    let red = [1.0, 0.0, 0.0, 1.0];
+    // An empty range inside "é" styles nothing.
+    let text = TextBuilder::new(None)
+        .add_attributed_text(
+            "café au lait",
+            &Style::new(&font, 16.0, BLACK),
+            &[(4..4, Attribute::Color(red))],
+        )
+        .build();
+    assert!(clusters_with_color(&text, red).is_empty());
+}
+
+#[test]
+fn truncation() {
+    let font = font();
+    let paragraph = "The quick brown fox jumps over the lazy dog. \
+                     Pack my box with five dozen liquor jugs.";
+    let path = "/usr/local/share/fonts/truetype/noto/NotoSans-Regular.ttf";
+    let clip = TextBuilder::new(Some(200.0))
+        .max_lines(2)
+        .add_text(&font, 14.0, BLACK, paragraph)
+        .build();
+    let end = TextBuilder::new(Some(200.0))
+        .max_height(40.0)
+        .overflow(Overflow::EndEllipsis)
+        .add_text(&font, 14.0, BLACK, paragraph)
+        .build();
+    let middle = TextBuilder::new(Some(200.0))
+        .overflow(Overflow::MiddleEllipsis)
+        .add_text(&font, 14.0, BLACK, path)
+        .build();
+    let start = TextBuilder::new(Some(200.0))
+        .overflow(Overflow::StartEllipsis)
+        .add_text(&font, 14.0, BLACK, path)
+        .build();
+    let fits = TextBuilder::new(Some(200.0))
+        .max_lines(1)
+        .overflow(Overflow::EndEllipsis)
+        .add_text(&font, 14.0, BLACK, "Short label")
+        .build();
+    assert!(clip.truncated && clip.lines.len() == 2);
+    assert_eq!(clip.visible_range, 0..clip.lines[1].range.end);
+    assert!(end.truncated && end.lines.len() == 2);
+    assert!(end.lines[1].elided.is_some());
+    assert!(end.lines[1].width <= 200.0);
+    assert_eq!(end.visible_range.end, end.lines[1].range.end);
+    for text in [&middle, &start] {
+        assert!(text.truncated && text.lines.len() == 1);
+        assert!(text.lines[0].width <= 200.0);
+    }
+    let elided = middle.lines[0].elided.clone().unwrap();
+    assert!(elided.start > 0 && elided.end < path.len());
+    assert_eq!(middle.visible_range, 0..path.len());
+    assert!(path[start.visible_range.clone()].ends_with("Regular.ttf"));
+    assert!(!fits.truncated && fits.lines[0].elided.is_none());
+    check(
+        "truncation",
+        &render(
+            220,
+            150,
+            WHITE,
+            &[
+                (10.0, 10.0, &clip),
+                (10.0, 55.0, &end),
+                (10.0, 100.0, &middle),
+                (10.0, 125.0, &start),
+            ],
+        ),
+    );
+}
+
+#[test]
+fn truncation_narrower_than_ellipsis() {
+    let font = font();
+    let path = "/usr/local/share/fonts/truetype/noto/NotoSans-Regular.ttf";
+    for overflow in [
+        Overflow::StartEllipsis,
+        Overflow::MiddleEllipsis,
+        Overflow::EndEllipsis,
+    ] {
+        let text = TextBuilder::new(Some(4.0))
+            .max_lines(1)
+            .overflow(overflow)
+            .add_text(&font, 14.0, BLACK, path)
+            .build();
+        let line = &text.lines[0];
+        assert!(text.truncated);
+        assert!(line.width <= 4.0);
+        // Only the cluster of the ellipsis remains.
+        let elided = line.elided.clone().unwrap();
+        assert!(line
+            .runs
+            .iter()
+            .flat_map(|run| &run.clusters)
+            .all(|cluster| cluster.source == elided));
+    }
+}
+
+#[test]
+fn measurement() {
+    let font = font();
+    let texts = [
+        "The quick brown fox jumps over the lazy dog.\n\nPack my box with five dozen liquor jugs.",
+        "abc שלום עולם 123 def",
+        "",
+    ];
+    // Mixed font sizes so that line heights vary.
+    let builder = |max_width, text| {
+        TextBuilder::new(max_width)
+            .add_text(&font, 14.0, BLACK, "Label: ")
+            .add_text(&font, 20.0, BLACK, text)
+    };
+    let widest = |text: &Text| text.lines.iter().map(|line| line.width).fold(0.0, f32::max);
+    // One builder measures the whole batch.
+    let mut measurer = TextBuilder::new(None);
+    for text in texts {
+        measurer = measurer
+            .add_text(&font, 14.0, BLACK, "Label: ")
+            .add_text(&font, 20.0, BLACK, text);
+        let measurement = measurer.measure();
+        let min_width = measurement.min_content_width();
+        let max_width = measurement.max_content_width();
+        assert_eq!(max_width, widest(&builder(None, text).build()));
+        assert!(min_width > 0.0 && min_width <= max_width);
+        // Words aren't broken at the minimum content width.
+        let narrowest = builder(Some(min_width), text).build();
+        assert_eq!(widest(&narrowest), min_width);
+        for width in [min_width, 60.0, 150.0, max_width] {
+            let built = builder(Some(width), text).build();
+            let last = built.lines.last().unwrap();
+            assert_eq!(measurement.line_count(width), built.lines.len());
+            assert_eq!(
+                measurement.height(width),
+                (last.y + last.ascent + last.descent).round()
+            );
+        }
+    }
+    let clamped = TextBuilder::new(None)
+        .max_lines(2)
+        .add_text(&font, 14.0, BLACK, texts[0])
+        .measure();
+    assert_eq!(clamped.line_count(100.0), 2);
+}
+
+#[test]
+fn measurement_without_wrapping() {
+    let font = font();
+    let path = "/usr/local/share/fonts/truetype/noto/NotoSans-Regular.ttf";
+    let text = "The quick brown fox jumps over the lazy dog.";
+    for overflow in [Overflow::StartEllipsis, Overflow::MiddleEllipsis] {
+        for text in [path, text] {
+            let measurement = TextBuilder::new(None)
+                .overflow(overflow)
+                .add_text(&font, 14.0, BLACK, text)
+                .measure();
+            let max_width = measurement.max_content_width();
+            assert_eq!(measurement.min_content_width(), max_width);
+            assert_eq!(measurement.line_count(max_width / 2.0), 1);
+        }
+    }
+}